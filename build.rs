@@ -1,4 +1,67 @@
+const PROTO: &str = "proto/webrtc.proto";
+const HEALTH_PROTO: &str = "proto/health.proto";
+const SERDE_DERIVE: &str = "#[derive(serde::Serialize, serde::Deserialize)]";
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/webrtc.proto")?;
+    // Messages are also (de)serialized as JSON by the REST gateway with serde.
+    // Field names are camelCase like the protobuf JSON mapping, but enums are
+    // their numeric values and timestamps `{seconds, nanos}` objects rather
+    // than the names and RFC 3339 strings of the canonical mapping.
+    let proto = std::fs::read_to_string(PROTO)?;
+    let mut builder = tonic_build::configure()
+        .type_attribute(".webrtc", SERDE_DERIVE)
+        .field_attribute(
+            ".webrtc.SessionStats.start_time",
+            "#[serde(with = \"crate::rest::timestamp\")]",
+        )
+        .field_attribute(
+            ".webrtc.SessionStats.stop_time",
+            "#[serde(with = \"crate::rest::timestamp\")]",
+        )
+        .field_attribute(
+            ".webrtc.MediaFlow.last_transition",
+            "#[serde(with = \"crate::rest::timestamp\")]",
+        )
+        .field_attribute(
+            ".webrtc.Violation.first_violated",
+            "#[serde(with = \"crate::rest::timestamp\")]",
+        )
+        .field_attribute(
+            ".webrtc.Report.generated_at",
+            "#[serde(with = \"crate::rest::timestamp\")]",
+        )
+        .field_attribute(
+            ".webrtc.LogLine.time",
+            "#[serde(with = \"crate::rest::timestamp\")]",
+        )
+        // `{"setBandwidth": {..}}` rather than `{"transform": {"setBandwidth": {..}}}`,
+        // a path without the leading dot so the fields of the oneof don't match it
+        .field_attribute("webrtc.SdpTransform.transform", "#[serde(flatten)]");
+    let mut messages: Vec<(&str, bool)> = vec![];
+
+    for line in proto.lines().map(str::trim) {
+        if let Some(message) = word_after(line, "message ") {
+            messages.push((message, false));
+        } else if let (Some(_), Some(message)) = (word_after(line, "oneof "), messages.last_mut()) {
+            message.1 = true;
+        }
+    }
+
+    // `default` is invalid on the enum generated for a oneof, which also
+    // receives the attributes of its message, but the oneof field is optional
+    for (message, has_oneof) in messages {
+        let attribute = if has_oneof {
+            "#[serde(rename_all = \"camelCase\")]"
+        } else {
+            "#[serde(default, rename_all = \"camelCase\")]"
+        };
+        builder = builder.type_attribute(format!(".webrtc.{}", message), attribute);
+    }
+
+    builder.compile(&[PROTO, HEALTH_PROTO], &["proto"])?;
     Ok(())
 }
+
+fn word_after<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    line.strip_prefix(prefix)?.split_whitespace().next()
+}
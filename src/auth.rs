@@ -0,0 +1,188 @@
+use crate::config::CONFIG;
+use crate::data::Data;
+use crate::error::{Result, ServerError};
+use crate::session::Session;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+pub(crate) const AUTHORIZATION_METADATA: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// The bearer token a request was authenticated with.
+///
+/// Sessions keep the token of the request that created them so quotas can
+/// be enforced per token.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Token(pub(crate) String);
+
+/// Check the bearer token of a request against the configured tokens.
+///
+/// Returns `None` when no tokens are configured, i.e. auth is disabled.
+pub(crate) fn authenticate(metadata: &MetadataMap) -> Result<Option<Token>> {
+    CONFIG
+        .auth_tokens
+        .as_ref()
+        .map(|tokens| {
+            let admin_tokens = CONFIG.admin_tokens.iter().flatten();
+            authenticate_with(metadata, tokens.iter().chain(admin_tokens))
+        })
+        .transpose()
+}
+
+fn authenticate_with<'a>(
+    metadata: &MetadataMap,
+    tokens: impl IntoIterator<Item = &'a String>,
+) -> Result<Token> {
+    let token = metadata
+        .get(AUTHORIZATION_METADATA)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+        .ok_or_else(|| ServerError::Unauthenticated("missing bearer token".into()))?;
+
+    // compare with every token so the time taken doesn't reveal a match
+    let valid = tokens.into_iter().fold(false, |valid, allowed| {
+        constant_time_eq(allowed.as_bytes(), token.as_bytes()) | valid
+    });
+
+    if !valid {
+        return Err(ServerError::Unauthenticated("invalid bearer token".into()));
+    }
+
+    Ok(Token(token.into()))
+}
+
+/// Compare in time that only depends on the lengths of `a` and `b`.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub(crate) fn is_admin(token: &Token) -> bool {
+    CONFIG
+        .admin_tokens
+        .iter()
+        .flatten()
+        .any(|admin| constant_time_eq(admin.as_bytes(), token.0.as_bytes()))
+}
+
+/// Fail unless the session was created with the token, or the token is an admin token.
+///
+/// Sessions are shared by everyone when auth is disabled.
+pub(crate) fn ensure_owner(session: &Session, token: Option<&Token>) -> Result<()> {
+    match token {
+        Some(token) if session.token.as_ref() != Some(token) && !is_admin(token) => {
+            Err(ServerError::PermissionDenied(format!(
+                "session {} belongs to another token",
+                session.id
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fail unless the token may change server-wide settings.
+pub(crate) fn ensure_admin(token: Option<&Token>) -> Result<()> {
+    match token {
+        Some(token) if !is_admin(token) => Err(ServerError::PermissionDenied(
+            "only admin tokens can change the server config".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The token a request was authenticated with by the `Authenticator`.
+pub(crate) fn token<T>(request: &Request<T>) -> Option<Token> {
+    request.extensions().get::<Token>().cloned()
+}
+
+/// Rejects gRPC requests without a valid bearer token and attaches the
+/// token to the request for the handlers.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Authenticator;
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> std::result::Result<Request<()>, Status> {
+        if let Some(token) = authenticate(request.metadata())? {
+            request.extensions_mut().insert(token);
+        }
+
+        Ok(request)
+    }
+}
+
+/// Fail if the token already has its quota of sessions that aren't stopped.
+pub(crate) fn ensure_session_quota(data: &Data, token: Option<&Token>) -> Result<()> {
+    match (token, CONFIG.token_max_sessions) {
+        (Some(token), Some(max_sessions)) if data.active_sessions_of(token) >= max_sessions => {
+            Err(ServerError::QuotaExceeded(format!(
+                "the token is limited to {} active sessions",
+                max_sessions
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fail if creating `count` peer connections would exceed the quota of the token.
+pub(crate) fn ensure_peer_connection_quota(
+    data: &Data,
+    token: Option<&Token>,
+    count: usize,
+) -> Result<()> {
+    match (token, CONFIG.token_max_peer_connections) {
+        (Some(token), Some(max_peer_connections))
+            if data.peer_connections_of(token) + count > max_peer_connections =>
+        {
+            Err(ServerError::QuotaExceeded(format!(
+                "the token is limited to {} peer connections",
+                max_peer_connections
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::metadata::MetadataValue;
+
+    fn metadata(authorization: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            AUTHORIZATION_METADATA,
+            MetadataValue::from_str(authorization).unwrap(),
+        );
+        metadata
+    }
+
+    #[test]
+    fn it_authenticates_a_bearer_token() {
+        let tokens = vec!["secret".to_string()];
+        let token = authenticate_with(&metadata("Bearer secret"), &tokens).unwrap();
+        assert!(authenticate_with(&metadata("Bearer secre"), &tokens).is_err());
+
+        assert_eq!(token, Token("secret".into()));
+    }
+
+    #[test]
+    fn it_rejects_a_missing_or_invalid_token() {
+        let tokens = vec!["secret".to_string()];
+
+        for metadata in [
+            MetadataMap::new(),
+            metadata("Bearer nope"),
+            metadata("secret"),
+        ] {
+            let error = authenticate_with(&metadata, &tokens).unwrap_err();
+            assert_eq!(Status::from(error).code(), tonic::Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn it_compares_in_constant_time() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+    }
+}
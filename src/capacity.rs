@@ -0,0 +1,241 @@
+//! Admission control, rejecting sessions and peer connections beyond the
+//! configured capacity of the host.
+
+use crate::config::CONFIG;
+use crate::data::Data;
+use crate::error::{Result, ServerError};
+use crate::server::webrtc;
+use crate::shutdown;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use std::time::Duration;
+use sysinfo::{get_current_pid, Pid, ProcessExt, ProcessorExt, System, SystemExt};
+use tokio::select;
+use tokio::sync::watch;
+use tracing::error;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The latest sample of the resources used by the host and the server.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ResourceUsage {
+    // of all cores of the host
+    pub(crate) cpu_percent: f64,
+    // resident memory of the server
+    pub(crate) memory_mb: f64,
+}
+
+lazy_static! {
+    static ref RESOURCE_USAGE: RwLock<ResourceUsage> = RwLock::new(ResourceUsage::default());
+}
+
+/// Sample CPU and memory usage every second until shutdown, CPU usage needs
+/// the time between two samples.
+pub(crate) fn start_resource_sampling(shutdown: watch::Receiver<bool>) {
+    let pid = match get_current_pid() {
+        Ok(pid) => pid,
+        Err(e) => {
+            error!("Could not sample resource usage: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(sample_resources(pid, shutdown));
+}
+
+async fn sample_resources(pid: Pid, shutdown: watch::Receiver<bool>) {
+    let mut system = System::new();
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    let shutdown = shutdown::requested(shutdown);
+    tokio::pin!(shutdown);
+
+    loop {
+        select! {
+            _ = interval.tick() => {}
+            _ = &mut shutdown => break,
+        }
+
+        system.refresh_cpu();
+        system.refresh_process(pid);
+
+        let memory_mb = system
+            .process(pid)
+            .map(|process| process.memory() as f64 / 1024.0)
+            .unwrap_or_default();
+
+        *RESOURCE_USAGE.write() = ResourceUsage {
+            cpu_percent: system.global_processor_info().cpu_usage().into(),
+            memory_mb,
+        };
+    }
+}
+
+pub(crate) fn resource_usage() -> ResourceUsage {
+    *RESOURCE_USAGE.read()
+}
+
+/// Usage of a limit, which is unlimited when `None`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Usage {
+    pub(crate) used: f64,
+    pub(crate) limit: Option<f64>,
+}
+
+impl Usage {
+    fn new(used: f64, limit: Option<f64>) -> Self {
+        Self { used, limit }
+    }
+
+    pub(crate) fn headroom(&self) -> Option<f64> {
+        self.limit.map(|limit| (limit - self.used).max(0.0))
+    }
+
+    /// Fail if adding `count` would exceed the limit.
+    fn ensure_room(&self, name: &str, count: f64) -> Result<()> {
+        match self.limit {
+            Some(limit) if self.used + count > limit => Err(ServerError::CapacityExceeded(
+                format!("{} is limited to {}, currently {}", name, limit, self.used),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<Usage> for webrtc::CapacityUsage {
+    fn from(usage: Usage) -> Self {
+        webrtc::CapacityUsage {
+            used: usage.used,
+            limit: usage.limit.unwrap_or_default(),
+            headroom: usage.headroom().unwrap_or_default(),
+        }
+    }
+}
+
+/// The usage of every capacity limit of the server.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Capacity {
+    pub(crate) sessions: Usage,
+    pub(crate) peer_connections: Usage,
+    pub(crate) cpu_percent: Usage,
+    pub(crate) memory_mb: Usage,
+}
+
+impl Capacity {
+    pub(crate) fn of(data: &Data) -> Self {
+        let resource_usage = resource_usage();
+
+        Self {
+            sessions: Usage::new(
+                data.active_session_count() as f64,
+                CONFIG.max_sessions.map(|max| max as f64),
+            ),
+            peer_connections: Usage::new(
+                data.peer_connection_count() as f64,
+                CONFIG.max_peer_connections.map(|max| max as f64),
+            ),
+            cpu_percent: Usage::new(resource_usage.cpu_percent, CONFIG.max_cpu_percent),
+            memory_mb: Usage::new(
+                resource_usage.memory_mb,
+                CONFIG.max_memory_mb.map(|max| max as f64),
+            ),
+        }
+    }
+
+    // CPU and memory are at capacity when they reach their limit
+    fn ensure_resources(&self) -> Result<()> {
+        self.cpu_percent.ensure_room("CPU usage (%)", 0.0)?;
+        self.memory_mb.ensure_room("Memory usage (MB)", 0.0)
+    }
+
+    /// Fail if the server can't take another session.
+    pub(crate) fn ensure_session_room(&self) -> Result<()> {
+        self.sessions.ensure_room("Sessions", 1.0)?;
+        self.ensure_resources()
+    }
+
+    /// Fail if the server can't take `count` more peer connections, in a
+    /// session that already has `session_peer_connections`.
+    pub(crate) fn ensure_peer_connection_room(
+        &self,
+        session_peer_connections: usize,
+        count: usize,
+    ) -> Result<()> {
+        session_usage(session_peer_connections)
+            .ensure_room("Peer connections per session", count as f64)?;
+        self.peer_connections
+            .ensure_room("Peer connections", count as f64)?;
+        self.ensure_resources()
+    }
+
+    /// Fail if any limit has been reached, i.e. the server isn't ready for more load.
+    pub(crate) fn ensure_room(&self) -> Result<()> {
+        self.sessions.ensure_room("Sessions", 1.0)?;
+        self.peer_connections.ensure_room("Peer connections", 1.0)?;
+        self.ensure_resources()
+    }
+
+    pub(crate) fn to_proto(self, session_peer_connections: usize) -> webrtc::Capacity {
+        webrtc::Capacity {
+            sessions: Some(self.sessions.into()),
+            peer_connections: Some(self.peer_connections.into()),
+            session_peer_connections: Some(session_usage(session_peer_connections).into()),
+            cpu_percent: Some(self.cpu_percent.into()),
+            memory_mb: Some(self.memory_mb.into()),
+        }
+    }
+}
+
+fn session_usage(session_peer_connections: usize) -> Usage {
+    Usage::new(
+        session_peer_connections as f64,
+        CONFIG
+            .max_peer_connections_per_session
+            .map(|max| max as f64),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_headroom() {
+        let usage = Usage::new(8.0, Some(10.0));
+
+        assert_eq!(Some(2.0), usage.headroom());
+        assert!(usage.ensure_room("Sessions", 2.0).is_ok());
+        assert!(usage.ensure_room("Sessions", 3.0).is_err());
+    }
+
+    #[test]
+    fn it_is_unlimited_without_a_limit() {
+        let usage = Usage::new(1000.0, None);
+
+        assert_eq!(None, usage.headroom());
+        assert!(usage.ensure_room("Sessions", 1.0).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_with_resource_exhausted() {
+        let error = Usage::new(95.0, Some(90.0))
+            .ensure_room("CPU usage (%)", 0.0)
+            .unwrap_err();
+
+        assert_eq!(
+            tonic::Code::ResourceExhausted,
+            tonic::Status::from(error).code()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_stops_sampling_on_shutdown() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let sampling = tokio::spawn(sample_resources(get_current_pid().unwrap(), shutdown_rx));
+
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), sampling)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+//! Exports the stats of every started session from a task of its own, so a
+//! slow session only delays itself.
+//!
+//! The stats of all peer connections are fetched concurrently, bounded across
+//! the server by `stats_concurrency`.  No DashMap guard is held while waiting
+//! on libwebrtc.
+
+use crate::config::CONFIG;
+use crate::data::Data;
+use crate::error::{Result, ServerError};
+use crate::metrics::write_collector_stats;
+use crate::session::SessionState;
+use crate::session_log;
+use futures::stream::{self, StreamExt};
+use lazy_static::lazy_static;
+use libwebrtc::peer_connection::{PeerConnection, PeerConnectionStats};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::Semaphore;
+use tokio::time::{interval, interval_at, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, Level};
+
+// How often to look for sessions that were started since the last look
+const SUPERVISOR_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    // get_stats calls in flight, across all sessions
+    static ref PERMITS: Semaphore = Semaphore::new(CONFIG.stats_concurrency);
+}
+
+/// How late a tick of the stats interval fired.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Tick {
+    pub(crate) lateness: Duration,
+    /// Ticks that were skipped because the previous collection overran them
+    pub(crate) skipped: u64,
+}
+
+impl Tick {
+    pub(crate) fn of(lateness: Duration, period: Duration) -> Self {
+        Self {
+            lateness,
+            skipped: (lateness.as_nanos() / period.as_nanos().max(1)) as u64,
+        }
+    }
+
+    /// A tick is late once it fires a tenth of the period after its deadline
+    pub(crate) fn is_late(&self, period: Duration) -> bool {
+        self.lateness > period / 10
+    }
+}
+
+/// Counts of the stats ticks of a session in its current run.
+#[derive(Debug, Default)]
+pub(crate) struct StatsTicks {
+    pub(crate) ticks: AtomicU64,
+    pub(crate) late: AtomicU64,
+    pub(crate) skipped: AtomicU64,
+    pub(crate) last_lateness_ms: AtomicU64,
+}
+
+impl StatsTicks {
+    pub(crate) fn record(&self, tick: &Tick, period: Duration) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.skipped.fetch_add(tick.skipped, Ordering::Relaxed);
+        self.last_lateness_ms
+            .store(tick.lateness.as_millis() as u64, Ordering::Relaxed);
+
+        if tick.is_late(period) {
+            self.late.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Spawn a collection task for each started session that doesn't have one.
+pub(crate) fn start(data: Arc<Data>) {
+    tokio::spawn(async move {
+        let (done_tx, mut done_rx) = unbounded_channel();
+        let mut collecting = HashSet::new();
+        let mut supervisor = interval(SUPERVISOR_INTERVAL);
+
+        loop {
+            supervisor.tick().await;
+
+            while let Ok(session_id) = done_rx.try_recv() {
+                collecting.remove(&session_id);
+            }
+
+            let started: Vec<String> = data
+                .sessions
+                .iter()
+                .filter(|session| session.state == SessionState::Started)
+                .map(|session| session.id.clone())
+                .collect();
+
+            for session_id in started {
+                if collecting.insert(session_id.clone()) {
+                    tokio::spawn(collect_session(data.clone(), session_id, done_tx.clone()));
+                }
+            }
+        }
+    });
+}
+
+fn stats_interval_at(period: Duration) -> Interval {
+    let mut interval = interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    interval
+}
+
+// The state, stats interval and polling period of a session, None once it's gone
+fn schedule_of(data: &Data, session_id: &str) -> Option<(SessionState, Duration, Duration)> {
+    data.sessions.get(session_id).map(|session| {
+        (
+            session.state.clone(),
+            session.stats_interval(),
+            session.polling_state_s,
+        )
+    })
+}
+
+/// Export the stats of a session every stats interval until it's stopped.
+async fn collect_session(data: Arc<Data>, session_id: String, done: UnboundedSender<String>) {
+    debug!("Collecting stats for session {}", session_id);
+
+    let mut period = match schedule_of(&data, &session_id) {
+        Some((_, stats_interval, _)) => stats_interval,
+        None => return,
+    };
+    let mut interval = stats_interval_at(period);
+    let mut next_state_poll = Instant::now();
+
+    loop {
+        let deadline = interval.tick().await;
+        let tick = Tick::of(deadline.elapsed(), period);
+
+        let (state, stats_interval, polling_state) = match schedule_of(&data, &session_id) {
+            Some((SessionState::Stopped, ..)) | None => break,
+            Some(schedule) => schedule,
+        };
+
+        // UpdateServerConfig may have changed the default interval
+        if stats_interval != period {
+            period = stats_interval;
+            interval = stats_interval_at(period);
+            continue;
+        }
+
+        if state == SessionState::Paused {
+            continue;
+        }
+
+        // poll once per polling period
+        let should_poll_state = Instant::now() >= next_state_poll;
+        if should_poll_state {
+            next_state_poll = Instant::now() + polling_state;
+        }
+
+        let started = Instant::now();
+        export_session_stats(&data, &session_id, should_poll_state).await;
+        let collection_time = started.elapsed();
+
+        if let Some(session) = data.sessions.get(&session_id) {
+            session.stats_ticks.record(&tick, period);
+            session.evaluate_thresholds();
+
+            if tick.skipped > 0 {
+                session_log!(
+                    session.logs,
+                    Level::WARN,
+                    None,
+                    "Stats collection is {}ms late, skipped {} ticks",
+                    tick.lateness.as_millis(),
+                    tick.skipped
+                );
+            }
+        }
+
+        write_collector_stats(&tick, &session_id, collection_time);
+    }
+
+    debug!("Stopped collecting stats for session {}", session_id);
+    done.send(session_id).ok();
+}
+
+/// Fetch the stats of peer connections concurrently, within the server's bound.
+pub(crate) async fn fetch_stats(
+    peer_connections: Vec<(String, Arc<PeerConnection>)>,
+) -> Vec<(String, Result<PeerConnectionStats>)> {
+    stream::iter(peer_connections)
+        .map(|(pc_id, webrtc_peer_connection)| async move {
+            let _permit = PERMITS
+                .acquire()
+                .await
+                .expect("the stats semaphore is never closed");
+            let stats = webrtc_peer_connection
+                .get_stats()
+                .await
+                .map_err(|e| ServerError::GetStatsError(pc_id.clone(), e.into()));
+
+            (pc_id, stats)
+        })
+        .buffer_unordered(CONFIG.stats_concurrency)
+        .collect()
+        .await
+}
+
+/// Fetch the stats of every peer connection of a session and export them.
+pub(crate) async fn export_session_stats(data: &Data, session_id: &str, should_poll_state: bool) {
+    // the guard is dropped before waiting on libwebrtc
+    let peer_connections = match data.sessions.get(session_id) {
+        Some(session) => session.webrtc_peer_connections(),
+        None => return,
+    };
+
+    let samples = fetch_stats(peer_connections).await;
+
+    let session = match data.sessions.get(session_id) {
+        Some(session) => session,
+        None => return,
+    };
+
+    for (pc_id, stats) in samples {
+        // closed while its stats were being fetched
+        let mut pc = match session.peer_connections.get_mut(&pc_id) {
+            Some(pc) => pc,
+            None => continue,
+        };
+
+        match stats {
+            Ok(stats) => {
+                pc.export_stats(
+                    session_id,
+                    &stats,
+                    should_poll_state,
+                    &session.media_detection,
+                );
+                session.record_sample(&pc);
+            }
+            Err(e) => session_log!(
+                session.logs,
+                Level::ERROR,
+                Some(&pc_id),
+                "Failed to export stats for peer connection: {}",
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::new_session;
+
+    #[test]
+    fn it_counts_skipped_ticks() {
+        let period = Duration::from_millis(1000);
+
+        let on_time = Tick::of(Duration::from_millis(20), period);
+        assert_eq!(0, on_time.skipped);
+        assert!(!on_time.is_late(period));
+
+        let late = Tick::of(Duration::from_millis(2500), period);
+        assert_eq!(2, late.skipped);
+        assert!(late.is_late(period));
+    }
+
+    #[test]
+    fn it_records_ticks() {
+        let period = Duration::from_millis(1000);
+        let stats_ticks = StatsTicks::default();
+        stats_ticks.record(&Tick::of(Duration::from_millis(1), period), period);
+        stats_ticks.record(&Tick::of(Duration::from_millis(1200), period), period);
+
+        assert_eq!(2, stats_ticks.ticks.load(Ordering::Relaxed));
+        assert_eq!(1, stats_ticks.late.load(Ordering::Relaxed));
+        assert_eq!(1, stats_ticks.skipped.load(Ordering::Relaxed));
+        assert_eq!(1200, stats_ticks.last_lateness_ms.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn it_exports_stats_of_a_missing_session() {
+        let (_, data) = new_session();
+        export_session_stats(&data, "missing", true).await;
+    }
+}
@@ -1,36 +1,379 @@
+use crate::error::{Result, ServerError};
+use clap::{ArgEnum, Parser};
 use dotenv::dotenv;
 use lazy_static::lazy_static;
-use serde::Deserialize;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
-#[derive(Deserialize, Debug)]
+pub(crate) const MIN_STATS_INTERVAL_MS: u64 = 100;
+
+#[derive(ArgEnum, Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsBackend {
+    Statsd,
+    /// Write metrics to the log, under the `metrics` target
+    Log,
+}
+
+#[derive(ArgEnum, Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+/// The configuration of the server.
+///
+/// Values are read from, in increasing precedence: the defaults, a TOML or
+/// YAML file passed with `--config`, environment variables (including a
+/// `.env` file) and CLI flags.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub host: String,
-    pub port: String,
+    pub port: u16,
+    pub rest_port: Option<u16>,
+    /// Origins allowed to call the server from a browser, all when unset
+    pub cors_allowed_origins: Option<Vec<String>>,
+    pub cors_max_age_s: Option<u64>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// Require client certificates signed by this CA (mutual TLS)
+    pub tls_client_ca_path: Option<String>,
+    /// Bearer tokens accepted by the server, auth is disabled when unset
+    pub auth_tokens: Option<Vec<String>>,
+    /// Tokens that can also update the server config and use every session
+    pub admin_tokens: Option<Vec<String>>,
+    pub token_max_sessions: Option<usize>,
+    pub token_max_peer_connections: Option<usize>,
+    /// Seconds to stop sessions and finish requests on SIGINT or SIGTERM
+    pub drain_timeout_s: u64,
+    /// Capacity limits, sessions only count while they aren't stopped
+    pub max_sessions: Option<usize>,
+    pub max_peer_connections: Option<usize>,
+    pub max_peer_connections_per_session: Option<usize>,
+    /// CPU usage of the host, across all cores
+    pub max_cpu_percent: Option<f64>,
+    /// Resident memory of the server
+    pub max_memory_mb: Option<u64>,
+    /// WebRTC factories per session, peer connections are spread across them
+    pub factory_count: usize,
+    /// Seconds between polls of peer connection states, for sessions created without one
+    pub default_polling_state_s: u64,
+    /// Milliseconds between exports of peer connection stats
+    pub stats_interval_ms: u64,
+    /// Peer connection stats fetched at once, across all sessions
+    pub stats_concurrency: usize,
+    pub metrics_backends: Vec<MetricsBackend>,
     pub statsd_host: String,
-    pub statsd_port: String,
+    pub statsd_port: u16,
+    pub log_format: LogFormat,
+    /// Export traces to this OpenTelemetry collector, e.g. http://localhost:4317
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "[::1]".into(),
+            port: 50051,
+            rest_port: None,
+            cors_allowed_origins: None,
+            cors_max_age_s: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            auth_tokens: None,
+            admin_tokens: None,
+            token_max_sessions: None,
+            token_max_peer_connections: None,
+            drain_timeout_s: 10,
+            max_sessions: None,
+            max_peer_connections: None,
+            max_peer_connections_per_session: None,
+            max_cpu_percent: None,
+            max_memory_mb: None,
+            factory_count: num_cpus::get(),
+            default_polling_state_s: 1,
+            stats_interval_ms: 1000,
+            stats_concurrency: 64,
+            metrics_backends: vec![MetricsBackend::Statsd],
+            statsd_host: "127.0.0.1".into(),
+            statsd_port: 9125,
+            log_format: LogFormat::Pretty,
+            otlp_endpoint: None,
+        }
+    }
 }
 
+impl Config {
+    fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(ServerError::InvalidConfig(message.into()));
+
+        for port in std::iter::once(self.port).chain(self.rest_port) {
+            if format!("{}:{}", self.host, port)
+                .parse::<SocketAddr>()
+                .is_err()
+            {
+                return invalid(&format!("{}:{} is not a valid address", self.host, port));
+            }
+        }
+
+        if self.rest_port == Some(self.port) {
+            return invalid("rest_port must differ from port");
+        }
+
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return invalid("tls_cert_path and tls_key_path must be set together");
+        }
+
+        if self.tls_client_ca_path.is_some() && self.tls_cert_path.is_none() {
+            return invalid("tls_client_ca_path requires tls_cert_path and tls_key_path");
+        }
+
+        if self.admin_tokens.is_some() && self.auth_tokens.is_none() {
+            return invalid("admin_tokens requires auth_tokens");
+        }
+
+        if self.factory_count == 0 {
+            return invalid("factory_count must be at least 1");
+        }
+
+        if self.default_polling_state_s == 0 {
+            return invalid("default_polling_state_s must be at least 1");
+        }
+
+        if self.stats_interval_ms < MIN_STATS_INTERVAL_MS {
+            return invalid(&format!(
+                "stats_interval_ms must be at least {}",
+                MIN_STATS_INTERVAL_MS
+            ));
+        }
+
+        if self.stats_concurrency == 0 {
+            return invalid("stats_concurrency must be at least 1");
+        }
+
+        if let Some(max_cpu_percent) = self.max_cpu_percent {
+            if !(max_cpu_percent > 0.0 && max_cpu_percent <= 100.0) {
+                return invalid("max_cpu_percent must be greater than 0 and at most 100");
+            }
+        }
+
+        if self.metrics_backends.contains(&MetricsBackend::Statsd) && self.statsd_host.is_empty() {
+            return invalid("statsd_host is required by the statsd metrics backend");
+        }
+
+        Ok(())
+    }
+}
+
+/// Overrides of the config file from CLI flags and environment variables,
+/// named after the fields of `Config`.
+#[derive(Parser, Serialize, Debug, Default)]
+#[clap(about, version)]
+struct Args {
+    /// A TOML or YAML config file
+    #[clap(long, env = "CONFIG_FILE")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    #[clap(long, env = "HOST")]
+    host: Option<String>,
+    #[clap(long, env = "PORT")]
+    port: Option<u16>,
+    #[clap(long, env = "REST_PORT")]
+    rest_port: Option<u16>,
+    #[clap(long, env = "CORS_ALLOWED_ORIGINS", use_value_delimiter = true)]
+    cors_allowed_origins: Option<Vec<String>>,
+    #[clap(long, env = "CORS_MAX_AGE_S")]
+    cors_max_age_s: Option<u64>,
+    #[clap(long, env = "TLS_CERT_PATH")]
+    tls_cert_path: Option<String>,
+    #[clap(long, env = "TLS_KEY_PATH")]
+    tls_key_path: Option<String>,
+    #[clap(long, env = "TLS_CLIENT_CA_PATH")]
+    tls_client_ca_path: Option<String>,
+    #[clap(
+        long,
+        env = "AUTH_TOKENS",
+        use_value_delimiter = true,
+        hide_env_values = true
+    )]
+    auth_tokens: Option<Vec<String>>,
+    #[clap(
+        long,
+        env = "ADMIN_TOKENS",
+        use_value_delimiter = true,
+        hide_env_values = true
+    )]
+    admin_tokens: Option<Vec<String>>,
+    #[clap(long, env = "TOKEN_MAX_SESSIONS")]
+    token_max_sessions: Option<usize>,
+    #[clap(long, env = "TOKEN_MAX_PEER_CONNECTIONS")]
+    token_max_peer_connections: Option<usize>,
+    #[clap(long, env = "DRAIN_TIMEOUT_S")]
+    drain_timeout_s: Option<u64>,
+    #[clap(long, env = "MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+    #[clap(long, env = "MAX_PEER_CONNECTIONS")]
+    max_peer_connections: Option<usize>,
+    #[clap(long, env = "MAX_PEER_CONNECTIONS_PER_SESSION")]
+    max_peer_connections_per_session: Option<usize>,
+    #[clap(long, env = "MAX_CPU_PERCENT")]
+    max_cpu_percent: Option<f64>,
+    #[clap(long, env = "MAX_MEMORY_MB")]
+    max_memory_mb: Option<u64>,
+    #[clap(long, env = "FACTORY_COUNT")]
+    factory_count: Option<usize>,
+    #[clap(long, env = "DEFAULT_POLLING_STATE_S")]
+    default_polling_state_s: Option<u64>,
+    #[clap(long, env = "STATS_INTERVAL_MS")]
+    stats_interval_ms: Option<u64>,
+    #[clap(long, env = "STATS_CONCURRENCY")]
+    stats_concurrency: Option<usize>,
+    #[clap(long, env = "METRICS_BACKENDS", arg_enum, use_value_delimiter = true)]
+    metrics_backends: Option<Vec<MetricsBackend>>,
+    #[clap(long, env = "STATSD_HOST")]
+    statsd_host: Option<String>,
+    #[clap(long, env = "STATSD_PORT")]
+    statsd_port: Option<u16>,
+    #[clap(long, env = "LOG_FORMAT", arg_enum)]
+    log_format: Option<LogFormat>,
+    #[clap(long, env = "OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+}
+
+static LOADED_CONFIG: OnceCell<Config> = OnceCell::new();
+
 // put the Config struct into a singleton CONFIG lazy_static
 lazy_static! {
-    pub static ref CONFIG: Config = get_config();
+    /// The config loaded by `init`, or the defaults if it wasn't called (e.g. in tests)
+    pub static ref CONFIG: &'static Config = LOADED_CONFIG.get_or_init(Config::default);
 }
 
-/// Use envy to deserialize environment variables into the Config struct
-fn get_config() -> Config {
+/// Load the config from the CLI flags, environment and config file.
+///
+/// Invalid flags exit with the usage of the server, other errors are returned.
+pub(crate) fn init() -> Result<()> {
     dotenv().ok();
 
-    envy::from_env::<Config>().unwrap_or_else(|error| panic!("Configuration Error: {:#?}", error))
+    let config = get_config(Args::parse())?;
+
+    LOADED_CONFIG
+        .set(config)
+        .map_err(|_| ServerError::InvalidConfig("the config is already loaded".into()))
+}
+
+fn get_config(args: Args) -> Result<Config> {
+    let mut values = match &args.config {
+        Some(path) => read_config_file(path)?,
+        None => Map::new(),
+    };
+    let overrides = serde_json::to_value(&args).map_err(invalid_config)?;
+
+    if let Value::Object(overrides) = overrides {
+        values.extend(overrides.into_iter().filter(|(_, value)| !value.is_null()));
+    }
+
+    let config: Config = serde_json::from_value(Value::Object(values)).map_err(invalid_config)?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+fn read_config_file(path: &Path) -> Result<Map<String, Value>> {
+    let invalid = |error: &dyn std::fmt::Display| {
+        ServerError::InvalidConfig(format!("{}: {}", path.display(), error))
+    };
+    let contents = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+    let values: Value = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(|e| invalid(&e))?,
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(|e| invalid(&e))?,
+        _ => return Err(invalid(&"expected a .toml, .yaml or .yml file")),
+    };
+
+    match values {
+        Value::Object(values) => Ok(values),
+        Value::Null => Ok(Map::new()),
+        _ => Err(invalid(&"expected a table of settings")),
+    }
+}
+
+fn invalid_config(error: serde_json::Error) -> ServerError {
+    ServerError::InvalidConfig(error.to_string())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile::NamedTempFile;
+
+    // deleted when dropped
+    fn config_file(extension: &str, contents: &str) -> NamedTempFile {
+        let file = tempfile::Builder::new()
+            .suffix(extension)
+            .tempfile()
+            .unwrap();
+        std::fs::write(file.path(), contents).unwrap();
+        file
+    }
 
     #[test]
     fn it_gets_a_config() {
-        let host = "123";
-        std::env::set_var("HOST", host.to_string());
-        let config = &CONFIG;
-        assert_eq!(config.host, host.to_string());
+        let config = get_config(Args::default()).unwrap();
+
+        assert_eq!(config.port, 50051);
+        assert_eq!(config.metrics_backends, vec![MetricsBackend::Statsd]);
+        assert_eq!(config.factory_count, num_cpus::get());
+    }
+
+    #[test]
+    fn it_overrides_the_config_file() {
+        let file = config_file(
+            ".toml",
+            "port = 50061\nstatsd_host = \"statsd\"\nmetrics_backends = [\"log\"]\n",
+        );
+        let config = get_config(Args {
+            config: Some(file.path().into()),
+            statsd_host: Some("localhost".into()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(config.port, 50061);
+        assert_eq!(config.statsd_host, "localhost");
+        assert_eq!(config.metrics_backends, vec![MetricsBackend::Log]);
+    }
+
+    #[test]
+    fn it_reads_a_yaml_config_file() {
+        let file = config_file(".yaml", "max_sessions: 5\nlog_format: json\n");
+        let config = get_config(Args {
+            config: Some(file.path().into()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(config.max_sessions, Some(5));
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn it_rejects_an_invalid_config() {
+        let file = config_file(".toml", "prot = 50061\n");
+        let unknown_field = get_config(Args {
+            config: Some(file.path().into()),
+            ..Default::default()
+        });
+        let no_factories = get_config(Args {
+            factory_count: Some(0),
+            ..Default::default()
+        });
+
+        assert!(matches!(unknown_field, Err(ServerError::InvalidConfig(_))));
+        assert!(matches!(no_factories, Err(ServerError::InvalidConfig(_))));
     }
 }
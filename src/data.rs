@@ -1,12 +1,20 @@
 use std::sync::Arc;
-use std::time::Duration;
 
+use crate::auth::{self, Token};
+use crate::capacity::Capacity;
+use crate::collector;
 use crate::error::{Result, ServerError};
-use crate::session::Session;
+use crate::idempotency::IdempotencyKeys;
+use crate::session::{Session, SessionState};
+use crate::stats::SessionStats;
+use dashmap::mapref::entry::Entry;
 use dashmap::mapref::one::Ref;
 use dashmap::DashMap;
-use log::info;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use tracing::{error, info};
 
+#[derive(Clone)]
 pub(crate) struct SharedState {
     pub(crate) data: Arc<Data>,
 }
@@ -21,30 +29,260 @@ impl std::fmt::Debug for SharedState {
 
 pub(crate) type Sessions = DashMap<String, Session>;
 
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Sessions and peer connections that are being created, counted like the
+/// ones that exist so concurrent requests can't exceed a limit together.
+#[derive(Debug, Default)]
+struct Reserved {
+    sessions: usize,
+    peer_connections: usize,
+    // keyed by session id
+    session_peer_connections: HashMap<String, usize>,
+    token_sessions: HashMap<Token, usize>,
+    token_peer_connections: HashMap<Token, usize>,
+}
+
+/// Room reserved by a create request, released when dropped, i.e. once the
+/// created session or peer connections were added or the request failed.
+#[derive(Debug)]
+pub(crate) struct Reservation<'a> {
+    data: &'a Data,
+    sessions: usize,
+    peer_connections: usize,
+    session_id: Option<String>,
+    token: Option<Token>,
+}
+
 /// The in-memory persistent data structure for the server.
 ///
 /// sessions: holds current and past sessions, keyed by session.id
+/// idempotency_keys: session ids, keyed by the idempotency key of the create request
+/// admission: held while checking limits and reserving room
 #[derive(Debug)]
 pub(crate) struct Data {
     pub(crate) sessions: Sessions,
+    pub(crate) idempotency_keys: IdempotencyKeys,
+    admission: Mutex<()>,
+    reserved: Mutex<Reserved>,
 }
 
 impl Data {
     pub(crate) fn new() -> Self {
         Self {
             sessions: Sessions::new(),
+            idempotency_keys: IdempotencyKeys::default(),
+            admission: Mutex::new(()),
+            reserved: Mutex::new(Reserved::default()),
         }
     }
 
-    // Add a new session to sessions (in internal state)
+    /// Reserve a session within the quota of the token and the capacity of the server.
+    pub(crate) fn reserve_session(&self, token: Option<&Token>) -> Result<Reservation<'_>> {
+        let _admission = self.admission.lock();
+        auth::ensure_session_quota(self, token)?;
+        Capacity::of(self).ensure_session_room()?;
+
+        Ok(self.reserve(1, 0, None, token))
+    }
+
+    /// Reserve `count` peer connections in the session within the quota of the
+    /// token and the capacity of the server.
+    pub(crate) fn reserve_peer_connections(
+        &self,
+        session: &Session,
+        token: Option<&Token>,
+        count: usize,
+    ) -> Result<Reservation<'_>> {
+        let _admission = self.admission.lock();
+        auth::ensure_peer_connection_quota(self, token, count)?;
+        Capacity::of(self)
+            .ensure_peer_connection_room(self.session_peer_connection_count(session), count)?;
+
+        Ok(self.reserve(0, count, Some(&session.id), token))
+    }
+
+    fn reserve(
+        &self,
+        sessions: usize,
+        peer_connections: usize,
+        session_id: Option<&str>,
+        token: Option<&Token>,
+    ) -> Reservation<'_> {
+        let mut reserved = self.reserved.lock();
+        reserved.sessions += sessions;
+        reserved.peer_connections += peer_connections;
+
+        if let Some(session_id) = session_id {
+            hold(
+                &mut reserved.session_peer_connections,
+                session_id.into(),
+                peer_connections,
+            );
+        }
+
+        if let Some(token) = token {
+            hold(&mut reserved.token_sessions, token.clone(), sessions);
+            hold(
+                &mut reserved.token_peer_connections,
+                token.clone(),
+                peer_connections,
+            );
+        }
+
+        Reservation {
+            data: self,
+            sessions,
+            peer_connections,
+            session_id: session_id.map(Into::into),
+            token: token.cloned(),
+        }
+    }
+
+    // Add a new session to sessions (in internal state), rejecting duplicate ids
     pub(crate) fn add_session(&self, session: Session) -> Result<()> {
         info!("Adding session: {:?}", session);
 
-        self.sessions.insert(session.id.clone(), session);
+        match self.sessions.entry(session.id.clone()) {
+            Entry::Occupied(entry) => Err(ServerError::SessionAlreadyExists(entry.key().clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(session);
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn ensure_session_id_available(&self, id: &str) -> Result<()> {
+        if self.sessions.contains_key(id) {
+            return Err(ServerError::SessionAlreadyExists(id.into()));
+        }
 
         Ok(())
     }
 
+    /// List sessions ordered by id, filtered by state, owner and a name substring.
+    ///
+    /// Pages start after the session id in `page_token`.  Returns the page and
+    /// the token of the next page, if there is one.
+    pub(crate) fn list_sessions(
+        &self,
+        state: Option<&SessionState>,
+        token: Option<&Token>,
+        name: &str,
+        page_size: usize,
+        page_token: &str,
+    ) -> (Vec<SessionStats>, Option<String>) {
+        let page_size = match page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => page_size.min(MAX_PAGE_SIZE),
+        };
+
+        let mut sessions: Vec<SessionStats> = self
+            .sessions
+            .iter()
+            .filter(|session| state.is_none() || state == Some(&session.state))
+            .filter(|session| token.is_none() || session.token.as_ref() == token)
+            .filter(|session| session.name.contains(name))
+            .filter(|session| page_token.is_empty() || session.id.as_str() > page_token)
+            .map(|session| session.value().into())
+            .collect();
+        sessions.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let next_page_token = if sessions.len() > page_size {
+            sessions.truncate(page_size);
+            sessions.last().map(|session| session.id.clone())
+        } else {
+            None
+        };
+
+        (sessions, next_page_token)
+    }
+
+    /// The number of sessions that aren't stopped, including reserved ones.
+    pub(crate) fn active_session_count(&self) -> usize {
+        let active = self
+            .sessions
+            .iter()
+            .filter(|session| session.state != SessionState::Stopped)
+            .count();
+
+        active + self.reserved.lock().sessions
+    }
+
+    pub(crate) fn peer_connection_count(&self) -> usize {
+        let peer_connections: usize = self
+            .sessions
+            .iter()
+            .map(|session| session.peer_connections.len())
+            .sum();
+
+        peer_connections + self.reserved.lock().peer_connections
+    }
+
+    /// The number of peer connections of the session, including reserved ones.
+    pub(crate) fn session_peer_connection_count(&self, session: &Session) -> usize {
+        let reserved = self.reserved.lock();
+        let session_reserved = reserved.session_peer_connections.get(&session.id);
+
+        session.peer_connections.len() + session_reserved.copied().unwrap_or_default()
+    }
+
+    /// The number of sessions created with the token that aren't stopped.
+    pub(crate) fn active_sessions_of(&self, token: &Token) -> usize {
+        let active = self
+            .sessions
+            .iter()
+            .filter(|session| {
+                session.token.as_ref() == Some(token) && session.state != SessionState::Stopped
+            })
+            .count();
+        let reserved = self.reserved.lock().token_sessions.get(token).copied();
+
+        active + reserved.unwrap_or_default()
+    }
+
+    /// The number of peer connections in sessions created with the token.
+    pub(crate) fn peer_connections_of(&self, token: &Token) -> usize {
+        let peer_connections: usize = self
+            .sessions
+            .iter()
+            .filter(|session| session.token.as_ref() == Some(token))
+            .map(|session| session.peer_connections.len())
+            .sum();
+        let reserved = self
+            .reserved
+            .lock()
+            .token_peer_connections
+            .get(token)
+            .copied();
+
+        peer_connections + reserved.unwrap_or_default()
+    }
+
+    /// Export a final stats sample for every started or paused session, then stop it.
+    pub(crate) async fn stop_sessions(&self) {
+        let session_ids: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|session| {
+                session.state == SessionState::Started || session.state == SessionState::Paused
+            })
+            .map(|session| session.id.clone())
+            .collect();
+
+        for session_id in session_ids {
+            collector::export_session_stats(self, &session_id, true).await;
+
+            if let Some(mut session) = self.sessions.get_mut(&session_id) {
+                session
+                    .stop()
+                    .map_err(|e| error!("Failed to stop session {}: {}", session_id, e))
+                    .ok();
+            }
+        }
+    }
+
     pub(crate) fn get_session(&self, id: &str) -> Result<Ref<String, Session>> {
         let map = &self.sessions;
         let dashmap_value = map
@@ -53,46 +291,73 @@ impl Data {
 
         Ok(dashmap_value)
     }
+
+    /// Get a session, failing unless it was created with the token of the caller.
+    pub(crate) fn get_owned_session(
+        &self,
+        id: &str,
+        token: Option<&Token>,
+    ) -> Result<Ref<String, Session>> {
+        let session = self.get_session(id)?;
+        auth::ensure_owner(&session, token)?;
+
+        Ok(session)
+    }
 }
 
-impl SharedState {
-    pub(crate) fn start_metrics_collection(&self) {
-        let data = self.data.clone();
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut reserved = self.data.reserved.lock();
+        reserved.sessions -= self.sessions;
+        reserved.peer_connections -= self.peer_connections;
 
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(1));
-            let mut elapsed = 1;
+        if let Some(session_id) = &self.session_id {
+            release(
+                &mut reserved.session_peer_connections,
+                session_id,
+                self.peer_connections,
+            );
+        }
 
-            interval.tick().await;
+        if let Some(token) = &self.token {
+            release(&mut reserved.token_sessions, token, self.sessions);
+            release(
+                &mut reserved.token_peer_connections,
+                token,
+                self.peer_connections,
+            );
+        }
+    }
+}
 
-            loop {
-                for session in &data.sessions {
-                    let should_poll_state = elapsed % session.polling_state_s.as_secs() == 0;
+fn hold<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: K, count: usize) {
+    if count > 0 {
+        *counts.entry(key).or_default() += count;
+    }
+}
 
-                    session
-                        .value()
-                        .export_peer_connection_stats(should_poll_state)
-                        .await;
-                }
+fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K, count: usize) {
+    if let Some(reserved) = counts.get_mut(key) {
+        *reserved -= count;
 
-                // if a session exists, increment
-                // if no session exists, restart elapsed
-                if &data.sessions.len() != &0 {
-                    elapsed += 1;
-                } else {
-                    elapsed = 1;
-                }
+        if *reserved == 0 {
+            counts.remove(key);
+        }
+    }
+}
 
-                interval.tick().await;
-            }
-        });
+impl SharedState {
+    pub(crate) fn start_metrics_collection(&self) {
+        collector::start(self.data.clone());
     }
 }
 
 #[cfg(test)]
 mod tests {
-
+    use super::*;
+    use crate::log::LogLevel;
     use crate::session::tests::new_session;
+    use std::time::Duration;
 
     #[test]
     fn it_adds_and_gets_a_session() {
@@ -101,4 +366,102 @@ mod tests {
 
         assert_eq!(session_id, added_session.id);
     }
+
+    #[test]
+    fn it_rejects_a_duplicate_session() {
+        let (session_id, data) = new_session();
+        let session = Session::new(
+            session_id.clone(),
+            "Duplicate Session".into(),
+            Duration::from_secs(1),
+            LogLevel::None,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            data.add_session(session),
+            Err(ServerError::SessionAlreadyExists(_))
+        ));
+        assert_eq!("New Session", data.get_session(&session_id).unwrap().name);
+    }
+
+    #[test]
+    fn it_lists_sessions() {
+        let (session_id, data) = new_session();
+        let other_session = Session::new(
+            "other".into(),
+            "Other Session".into(),
+            Duration::from_secs(1),
+            LogLevel::None,
+        )
+        .unwrap();
+        data.add_session(other_session).unwrap();
+        data.sessions.get_mut("other").unwrap().start().unwrap();
+
+        let (sessions, next_page_token) = data.list_sessions(None, None, "", 0, "");
+        assert_eq!(2, sessions.len());
+        assert_eq!(None, next_page_token);
+
+        let (sessions, _) = data.list_sessions(Some(&SessionState::Started), None, "", 0, "");
+        assert_eq!(1, sessions.len());
+        assert_eq!("other", sessions[0].id);
+
+        let (sessions, _) = data.list_sessions(None, None, "New", 0, "");
+        assert_eq!(session_id, sessions[0].id);
+
+        let (first_page, next_page_token) = data.list_sessions(None, None, "", 1, "");
+        assert_eq!(1, first_page.len());
+        let (second_page, next_page_token) =
+            data.list_sessions(None, None, "", 1, &next_page_token.unwrap());
+        assert_eq!(1, second_page.len());
+        assert_ne!(first_page[0].id, second_page[0].id);
+        assert_eq!(None, next_page_token);
+    }
+
+    #[test]
+    fn it_counts_reserved_sessions_until_released() {
+        let data = Data::new();
+        let token = Token("token".into());
+        let reservation = data.reserve_session(Some(&token)).unwrap();
+
+        assert_eq!(1, data.active_session_count());
+        assert_eq!(1, data.active_sessions_of(&token));
+        assert_eq!(0, data.peer_connections_of(&token));
+
+        drop(reservation);
+        assert_eq!(0, data.active_session_count());
+        assert_eq!(0, data.active_sessions_of(&token));
+    }
+
+    #[test]
+    fn it_checks_the_owner_of_a_session() {
+        let (session_id, data) = new_session();
+        let owner = Token("owner".into());
+        let other = Token("other".into());
+        data.sessions.get_mut(&session_id).unwrap().token = Some(owner.clone());
+
+        assert!(data.get_owned_session(&session_id, Some(&owner)).is_ok());
+        assert!(data.get_owned_session(&session_id, None).is_ok());
+        assert!(matches!(
+            data.get_owned_session(&session_id, Some(&other)),
+            Err(ServerError::PermissionDenied(_))
+        ));
+
+        let (sessions, _) = data.list_sessions(None, Some(&other), "", 0, "");
+        assert!(sessions.is_empty());
+        let (sessions, _) = data.list_sessions(None, Some(&owner), "", 0, "");
+        assert_eq!(1, sessions.len());
+    }
+
+    #[tokio::test]
+    async fn it_stops_running_sessions() {
+        let (session_id, data) = new_session();
+        data.sessions.get_mut(&session_id).unwrap().start().unwrap();
+
+        data.stop_sessions().await;
+
+        let session = data.get_session(&session_id).unwrap();
+        assert_eq!(SessionState::Stopped, session.state);
+        assert!(session.stop_time.is_some());
+    }
 }
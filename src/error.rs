@@ -1,41 +1,97 @@
+use core::fmt;
 use libwebrtc::error::WebRTCError;
-use log::error;
 use std::net::AddrParseError;
 use std::sync::{MutexGuard, PoisonError};
 use thiserror::Error;
-use tonic::Status;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Status};
+use tracing::error;
 
 pub(crate) type Result<T> = std::result::Result<T, ServerError>;
 
+// Keys of the structured error details attached to a status
+pub(crate) const SESSION_ID_METADATA: &str = "x-session-id";
+pub(crate) const PEER_CONNECTION_ID_METADATA: &str = "x-peer-connection-id";
+pub(crate) const WEBRTC_ERROR_KIND_METADATA: &str = "x-webrtc-error-kind";
+
+/// An error from libwebrtc, keeping the name of its variant so clients can
+/// tell errors apart without parsing the message.
+#[derive(Debug)]
+pub struct WebRTCErrorDetails {
+    pub kind: String,
+    pub message: String,
+}
+
+impl fmt::Display for WebRTCErrorDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<WebRTCError> for WebRTCErrorDetails {
+    fn from(error: WebRTCError) -> Self {
+        let kind = format!("{:?}", error)
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect();
+
+        Self {
+            kind,
+            message: error.to_string(),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ServerError {
     #[error("Could not create peer connection: {0}")]
-    CreatePeerConnectionError(String),
+    CreatePeerConnectionError(WebRTCErrorDetails),
+
+    #[error("Capacity exceeded: {0}")]
+    CapacityExceeded(String),
+
+    #[error("Could not add ice candidate: {0}")]
+    CouldNotAddIceCandidate(WebRTCErrorDetails),
+
+    #[error("Could not add track: {0}")]
+    CouldNotAddTrack(WebRTCErrorDetails),
 
     #[error("Could not create answer: {0}")]
-    CouldNotCreateAnswer(String),
+    CouldNotCreateAnswer(WebRTCErrorDetails),
+
+    #[error("Could not create loopback pair: {0}")]
+    CouldNotCreateLoopbackPair(String),
 
     #[error("Could not create offer: {0}")]
-    CouldNotCreateOffer(String),
+    CouldNotCreateOffer(WebRTCErrorDetails),
 
     #[error("Could not create track: {0}")]
-    CouldNotCreateTrack(String),
+    CouldNotCreateTrack(WebRTCErrorDetails),
 
     #[error("Could not create transceiver: {0}")]
-    CouldNotAddTransceiver(String),
+    CouldNotAddTransceiver(WebRTCErrorDetails),
 
     #[error("Could not parse SDP: {0}")]
-    CouldNotParseSdp(String),
+    CouldNotParseSdp(WebRTCErrorDetails),
 
     #[error("Could not set SDP: {0}")]
-    CouldNotSetSdp(String),
+    CouldNotSetSdp(WebRTCErrorDetails),
 
-    #[error("Could not retrieve stats for session {0}, peer connection {1}")]
-    GetStatsError(String, String),
+    #[error("Could not retrieve stats for peer connection {0}: {1}")]
+    GetStatsError(String, WebRTCErrorDetails),
+
+    #[error("A request with idempotency key {0} is in progress")]
+    IdempotencyKeyInUse(String),
 
     #[error("Internal error: {0}")]
     InternalError(String),
 
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Invalid configuration update: {0}")]
+    InvalidConfigUpdate(String),
+
     #[error("PeerConnection {0} does not exist")]
     InvalidPeerConnection(String),
 
@@ -45,14 +101,115 @@ pub enum ServerError {
     #[error("{0}")]
     InvalidStateError(String),
 
+    #[error("Invalid SDP transform: {0}")]
+    InvalidSdpTransform(String),
+
+    #[error("Invalid stats interval: {0}")]
+    InvalidStatsInterval(String),
+
     #[error("TimeStamp {0} is invalid")]
     InvalidTimeStampError(String),
 
+    #[error("PeerConnection {0} already exists")]
+    PeerConnectionAlreadyExists(String),
+
+    #[error("Session {0} already exists")]
+    SessionAlreadyExists(String),
+
     #[error("Parse error: {0}")]
     ParseError(String),
 
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+
     #[error("WebRTC error: {0}")]
-    WebRTCError(String),
+    WebRTCError(WebRTCErrorDetails),
+}
+
+impl ServerError {
+    /// The gRPC status code that best describes the error to a client.
+    pub(crate) fn code(&self) -> Code {
+        match self {
+            ServerError::InvalidPeerConnection(_) | ServerError::InvalidSessionError(_) => {
+                Code::NotFound
+            }
+            ServerError::PeerConnectionAlreadyExists(_) | ServerError::SessionAlreadyExists(_) => {
+                Code::AlreadyExists
+            }
+            ServerError::InvalidStateError(_)
+            | ServerError::CouldNotAddIceCandidate(_)
+            | ServerError::CouldNotCreateAnswer(_)
+            | ServerError::CouldNotCreateOffer(_)
+            | ServerError::CouldNotSetSdp(_) => Code::FailedPrecondition,
+            ServerError::CouldNotParseSdp(_)
+            | ServerError::InvalidConfigUpdate(_)
+            | ServerError::InvalidSdpTransform(_)
+            | ServerError::InvalidStatsInterval(_)
+            | ServerError::InvalidTimeStampError(_)
+            | ServerError::ParseError(_) => Code::InvalidArgument,
+            ServerError::IdempotencyKeyInUse(_) => Code::Aborted,
+            ServerError::GetStatsError(..) => Code::Unavailable,
+            ServerError::Unauthenticated(_) => Code::Unauthenticated,
+            ServerError::PermissionDenied(_) | ServerError::QuotaExceeded(_) => {
+                Code::PermissionDenied
+            }
+            ServerError::CapacityExceeded(_) => Code::ResourceExhausted,
+            ServerError::CreatePeerConnectionError(_)
+            | ServerError::CouldNotAddTrack(_)
+            | ServerError::CouldNotCreateLoopbackPair(_)
+            | ServerError::CouldNotCreateTrack(_)
+            | ServerError::CouldNotAddTransceiver(_)
+            | ServerError::InternalError(_)
+            | ServerError::InvalidConfig(_)
+            | ServerError::WebRTCError(_) => Code::Internal,
+        }
+    }
+
+    fn webrtc_error_details(&self) -> Option<&WebRTCErrorDetails> {
+        match self {
+            ServerError::CreatePeerConnectionError(details)
+            | ServerError::CouldNotAddIceCandidate(details)
+            | ServerError::CouldNotAddTrack(details)
+            | ServerError::CouldNotCreateAnswer(details)
+            | ServerError::CouldNotCreateOffer(details)
+            | ServerError::CouldNotCreateTrack(details)
+            | ServerError::CouldNotAddTransceiver(details)
+            | ServerError::CouldNotParseSdp(details)
+            | ServerError::CouldNotSetSdp(details)
+            | ServerError::GetStatsError(_, details)
+            | ServerError::WebRTCError(details) => Some(details),
+            _ => None,
+        }
+    }
+}
+
+// Invalid metadata values (e.g. non-ascii ids) are skipped rather than failing the status
+fn insert_metadata(status: &mut Status, key: &'static str, value: &str) {
+    if let Ok(value) = MetadataValue::from_str(value) {
+        status.metadata_mut().insert(key, value);
+    }
+}
+
+/// Attach the ids of a request to the status of a failed handler.
+pub(crate) trait StatusContext<T> {
+    fn context(self, session_id: &str, peer_connection_id: &str) -> std::result::Result<T, Status>;
+}
+
+impl<T> StatusContext<T> for Result<T> {
+    fn context(self, session_id: &str, peer_connection_id: &str) -> std::result::Result<T, Status> {
+        self.map_err(|error| {
+            let mut status = Status::from(error);
+            insert_metadata(&mut status, SESSION_ID_METADATA, session_id);
+            insert_metadata(&mut status, PEER_CONNECTION_ID_METADATA, peer_connection_id);
+            status
+        })
+    }
 }
 
 impl From<AddrParseError> for ServerError {
@@ -71,21 +228,83 @@ impl<T> From<PoisonError<MutexGuard<'_, T>>> for ServerError {
 
 impl From<ServerError> for Status {
     fn from(error: ServerError) -> Status {
-        error!("{:?}", error);
-        Status::internal(error.to_string())
+        Status::from(&error)
     }
 }
 
 impl From<&ServerError> for Status {
     fn from(error: &ServerError) -> Status {
         error!("{:?}", error);
-        Status::internal(error.to_string())
+
+        let mut status = Status::new(error.code(), error.to_string());
+
+        match error {
+            ServerError::InvalidSessionError(session_id)
+            | ServerError::SessionAlreadyExists(session_id) => {
+                insert_metadata(&mut status, SESSION_ID_METADATA, session_id)
+            }
+            ServerError::InvalidPeerConnection(peer_connection_id)
+            | ServerError::PeerConnectionAlreadyExists(peer_connection_id)
+            | ServerError::GetStatsError(peer_connection_id, _) => {
+                insert_metadata(&mut status, PEER_CONNECTION_ID_METADATA, peer_connection_id)
+            }
+            _ => {}
+        }
+
+        if let Some(details) = error.webrtc_error_details() {
+            insert_metadata(&mut status, WEBRTC_ERROR_KIND_METADATA, &details.kind);
+        }
+
+        status
     }
 }
 
 impl From<WebRTCError> for ServerError {
     fn from(value: WebRTCError) -> Self {
-        // A little lazy to have a single variant but we can improve this in the future.
-        Self::WebRTCError(value.to_string())
+        // Prefer mapping to a specific variant where the failing operation is known.
+        Self::WebRTCError(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_maps_errors_to_status_codes() {
+        let status = Status::from(ServerError::InvalidSessionError("abc".into()));
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.metadata().get(SESSION_ID_METADATA).unwrap(), "abc");
+
+        let status = Status::from(ServerError::InvalidStateError("stopped".into()));
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
+
+    #[test]
+    fn it_attaches_the_webrtc_error_kind() {
+        let error = WebRTCError::UnexpectedError("boom".into());
+        let status = Status::from(ServerError::CouldNotCreateOffer(error.into()));
+
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(
+            status.metadata().get(WEBRTC_ERROR_KIND_METADATA).unwrap(),
+            "UnexpectedError"
+        );
+    }
+
+    #[test]
+    fn it_attaches_request_ids() {
+        let result: Result<()> = Err(ServerError::InternalError("boom".into()));
+        let status = result.context("session", "pc").unwrap_err();
+
+        assert_eq!(status.code(), Code::Internal);
+        assert_eq!(
+            status.metadata().get(SESSION_ID_METADATA).unwrap(),
+            "session"
+        );
+        assert_eq!(
+            status.metadata().get(PEER_CONNECTION_ID_METADATA).unwrap(),
+            "pc"
+        );
     }
 }
@@ -1,41 +1,133 @@
+use crate::auth;
+use crate::call_session;
+use crate::capacity::Capacity;
+use crate::config::{CONFIG, MIN_STATS_INTERVAL_MS};
 use crate::data::SharedState;
-use crate::error::ServerError;
+use crate::error::{ServerError, StatusContext};
+use crate::idempotency::Claim;
+use crate::report;
+use crate::runtime_config;
+use crate::sdp_transform::{self, Transform};
 use crate::server::webrtc::{self};
-use crate::session::Session;
-use crate::{call_session, get_session_attribute};
+use crate::session::{Session, SessionState};
 use async_stream::stream;
 use futures::Stream;
 use libwebrtc::media_type::MediaType;
 use libwebrtc::sdp::SDPType;
 use libwebrtc::transceiver::TransceiverDirection;
-use log::{debug, error, info};
 use std::fmt::Debug;
 use std::pin::Pin;
 use std::result::Result;
 use std::time::Duration;
+use tracing::{debug, error, Span};
 
 use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
 use tonic::{Request, Response, Status};
 use webrtc::web_rtc_server::WebRtc;
 use webrtc::{
-    AddTrackRequest, AddTransceiverRequest, CreatePeerConnectionRequest, CreateSdpRequest,
-    CreateSdpResponse, CreateSessionRequest, CreateSessionResponse, Empty, GetStatsRequest,
-    GetStatsResponse, PeerConnectionObserverMessage, SetSdpRequest, SetSdpResponse,
-    StartSessionRequest, StopSessionRequest,
+    AddTrackRequest, AddTransceiverRequest, CreateLoopbackPairRequest, CreateLoopbackPairResponse,
+    CreatePeerConnectionRequest, CreatePeerConnectionResponse, CreateSdpRequest, CreateSdpResponse,
+    CreateSessionRequest, CreateSessionResponse, Empty, GetReportRequest, GetReportResponse,
+    GetStatsRequest, GetStatsResponse, ListPeerConnectionsRequest, ListPeerConnectionsResponse,
+    ListSessionsRequest, ListSessionsResponse, LogLine, PauseSessionRequest,
+    PeerConnectionObserverMessage, ResumeSessionRequest, SetSdpRequest, SetSdpResponse,
+    StartSessionRequest, StopSessionRequest, StopSessionResponse, StreamLogsRequest,
+    UpdateServerConfigRequest, UpdateServerConfigResponse,
 };
 
 type ObserverStream =
     Pin<Box<dyn Stream<Item = Result<PeerConnectionObserverMessage, Status>> + Send>>;
+type LogStream = Pin<Box<dyn Stream<Item = Result<LogLine, Status>> + Send>>;
+
+/// The ids a request is about, recorded on the span of the call
+trait TraceIds {
+    fn session_id(&self) -> &str {
+        ""
+    }
+
+    fn peer_connection_id(&self) -> &str {
+        ""
+    }
+}
+
+macro_rules! session_trace_ids {
+    ($($request:ty),*) => {
+        $(impl TraceIds for $request {
+            fn session_id(&self) -> &str {
+                &self.session_id
+            }
+        })*
+    };
+}
+
+macro_rules! peer_connection_trace_ids {
+    ($($request:ty),*) => {
+        $(impl TraceIds for $request {
+            fn session_id(&self) -> &str {
+                &self.session_id
+            }
+
+            fn peer_connection_id(&self) -> &str {
+                &self.peer_connection_id
+            }
+        })*
+    };
+}
+
+session_trace_ids!(
+    CreateSessionRequest,
+    StartSessionRequest,
+    PauseSessionRequest,
+    ResumeSessionRequest,
+    StopSessionRequest,
+    GetStatsRequest,
+    GetReportRequest,
+    ListPeerConnectionsRequest,
+    StreamLogsRequest
+);
+peer_connection_trace_ids!(
+    CreatePeerConnectionRequest,
+    CreateSdpRequest,
+    SetSdpRequest,
+    AddTrackRequest,
+    AddTransceiverRequest,
+    webrtc::ObserverRequest,
+    webrtc::GetTransceiversRequest
+);
+
+impl TraceIds for ListSessionsRequest {}
+impl TraceIds for UpdateServerConfigRequest {}
+
+impl TraceIds for CreateLoopbackPairRequest {
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    fn peer_connection_id(&self) -> &str {
+        &self.sender_peer_connection_id
+    }
+}
 
 // TODO: create a proc macro to inject requester and responder into each handler
-fn requester<T: Debug>(tag: &str, request: Request<T>) -> T {
+fn requester<T: Debug + TraceIds>(tag: &str, request: Request<T>) -> T {
     let request = request.into_inner();
-    info!("Request({}): {:?}", tag, request);
+    let span = Span::current();
+
+    if !request.session_id().is_empty() {
+        span.record("session_id", request.session_id());
+    }
+
+    if !request.peer_connection_id().is_empty() {
+        span.record("peer_connection_id", request.peer_connection_id());
+    }
+
+    debug!("Request({}): {:?}", tag, request);
     request
 }
 
 fn responder<T: Debug>(tag: &str, response: T) -> Result<Response<T>, Status> {
-    info!("Response({}): {:?}", tag, response);
+    debug!("Response({}): {:?}", tag, response);
     Ok(Response::new(response))
 }
 
@@ -97,15 +189,56 @@ impl WebRtc for SharedState {
         &self,
         request: Request<CreateSessionRequest>,
     ) -> Result<Response<CreateSessionResponse>, Status> {
+        let token = auth::token(&request);
         let CreateSessionRequest {
             session_id,
             name,
             polling_state_s,
             log_level,
+            idempotency_key,
+            stats_interval_ms,
+            media_detection,
+            thresholds,
         } = requester("create_session", request);
-        let polling_state_s = Duration::from_secs(polling_state_s);
-        let session = Session::new(session_id.clone(), name, polling_state_s, log_level.into())?;
+
+        // a retried request returns the session it created before
+        let reservation = match self.data.idempotency_keys.claim(idempotency_key)? {
+            Claim::Created(session_id) => {
+                let reply = webrtc::CreateSessionResponse { session_id };
+                return responder("create_session", reply);
+            }
+            Claim::Reserved(reservation) => reservation,
+        };
+
+        let session_id = if session_id.is_empty() {
+            nanoid::nanoid!()
+        } else {
+            session_id
+        };
+        self.data.ensure_session_id_available(&session_id)?;
+        let _room = self.data.reserve_session(token.as_ref())?;
+        let polling_state_s = match polling_state_s {
+            0 => Duration::from_secs(CONFIG.default_polling_state_s),
+            polling_state_s => Duration::from_secs(polling_state_s),
+        };
+        if stats_interval_ms != 0 && stats_interval_ms < MIN_STATS_INTERVAL_MS {
+            return Err(ServerError::InvalidStatsInterval(format!(
+                "stats_interval_ms must be at least {}",
+                MIN_STATS_INTERVAL_MS
+            ))
+            .into());
+        }
+        let mut session =
+            Session::new(session_id.clone(), name, polling_state_s, log_level.into())?;
+        session.token = token;
+        session.stats_interval = match stats_interval_ms {
+            0 => None,
+            stats_interval_ms => Some(Duration::from_millis(stats_interval_ms)),
+        };
+        session.media_detection = media_detection.map(Into::into).unwrap_or_default();
+        session.thresholds = thresholds.map(Into::into);
         self.data.add_session(session)?;
+        reservation.complete(session_id.clone());
         let reply = webrtc::CreateSessionResponse { session_id };
 
         responder("create_session", reply)
@@ -115,20 +248,56 @@ impl WebRtc for SharedState {
         &self,
         request: Request<StartSessionRequest>,
     ) -> Result<Response<Empty>, Status> {
+        let token = auth::token(&request);
         let session_id = requester("start_session", request).session_id;
+        self.data.get_owned_session(&session_id, token.as_ref())?;
         call_session!(self, session_id, start)?;
         let reply = Empty {};
 
         responder("start_session", reply)
     }
 
+    async fn pause_session(
+        &self,
+        request: Request<PauseSessionRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let token = auth::token(&request);
+        let session_id = requester("pause_session", request).session_id;
+        self.data.get_owned_session(&session_id, token.as_ref())?;
+        call_session!(self, session_id, pause)?;
+        let reply = Empty {};
+
+        responder("pause_session", reply)
+    }
+
+    async fn resume_session(
+        &self,
+        request: Request<ResumeSessionRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let token = auth::token(&request);
+        let session_id = requester("resume_session", request).session_id;
+        self.data.get_owned_session(&session_id, token.as_ref())?;
+        call_session!(self, session_id, resume)?;
+        let reply = Empty {};
+
+        responder("resume_session", reply)
+    }
+
     async fn stop_session(
         &self,
         request: Request<StopSessionRequest>,
-    ) -> Result<Response<Empty>, Status> {
+    ) -> Result<Response<StopSessionResponse>, Status> {
+        let token = auth::token(&request);
         let session_id = requester("stop_session", request).session_id;
+        self.data.get_owned_session(&session_id, token.as_ref())?;
         call_session!(self, session_id, stop)?;
-        let reply = webrtc::Empty {};
+        let verdict = self
+            .data
+            .get_owned_session(&session_id, token.as_ref())?
+            .verdict();
+        let reply = webrtc::StopSessionResponse {
+            verdict: verdict.as_ref().map(Into::into),
+        };
 
         responder("stop_session", reply)
     }
@@ -137,15 +306,91 @@ impl WebRtc for SharedState {
         &self,
         request: Request<GetStatsRequest>,
     ) -> Result<Response<GetStatsResponse>, Status> {
+        let token = auth::token(&request);
         let session_id = requester("get_stats", request).session_id;
+        self.data.get_owned_session(&session_id, token.as_ref())?;
         let stats = call_session!(self, session_id, get_stats).await?;
+        let session_peer_connections = stats.session.num_peer_connections as usize;
+        let capacity = Capacity::of(&self.data).to_proto(session_peer_connections);
         let reply = webrtc::GetStatsResponse {
             session: Some(stats.session.into()),
+            capacity: Some(capacity),
+            verdict: stats.verdict.as_ref().map(Into::into),
         };
 
         responder("get_stats", reply)
     }
 
+    async fn get_report(
+        &self,
+        request: Request<GetReportRequest>,
+    ) -> Result<Response<GetReportResponse>, Status> {
+        let token = auth::token(&request);
+        let request = requester("get_report", request);
+        let report = report::report(
+            &*self
+                .data
+                .get_owned_session(&request.session_id, token.as_ref())?,
+        );
+        let (content_type, body) = match request.format() {
+            webrtc::ReportFormat::Json => ("application/json", report::to_json(&report)?),
+            webrtc::ReportFormat::Html => ("text/html", report::to_html(&report)),
+        };
+        let reply = webrtc::GetReportResponse {
+            content_type: content_type.into(),
+            body,
+            report: Some(report),
+        };
+
+        responder("get_report", reply)
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let token = auth::token(&request);
+        let ListSessionsRequest {
+            state,
+            name,
+            page_size,
+            page_token,
+        } = requester("list_sessions", request);
+        let state = match state.as_str() {
+            "" => None,
+            state => Some(state.parse::<SessionState>()?),
+        };
+        // admin tokens list every session
+        let owner = token.filter(|token| !auth::is_admin(token));
+        let (sessions, next_page_token) = self.data.list_sessions(
+            state.as_ref(),
+            owner.as_ref(),
+            &name,
+            page_size as usize,
+            &page_token,
+        );
+        let reply = ListSessionsResponse {
+            sessions: sessions.into_iter().map(Into::into).collect(),
+            next_page_token: next_page_token.unwrap_or_default(),
+        };
+
+        responder("list_sessions", reply)
+    }
+
+    async fn list_peer_connections(
+        &self,
+        request: Request<ListPeerConnectionsRequest>,
+    ) -> Result<Response<ListPeerConnectionsResponse>, Status> {
+        let token = auth::token(&request);
+        let session_id = requester("list_peer_connections", request).session_id;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+        let reply = ListPeerConnectionsResponse {
+            peer_connections: session.list_peer_connections(),
+        };
+
+        responder("list_peer_connections", reply)
+    }
+
     // async fn get_peer_connection_stats(
     //     &self,
     //     request: Request<GetPeerConnectionStatsRequest>,
@@ -171,35 +416,139 @@ impl WebRtc for SharedState {
     async fn create_peer_connection(
         &self,
         request: Request<CreatePeerConnectionRequest>,
-    ) -> Result<Response<Empty>, Status> {
+    ) -> Result<Response<CreatePeerConnectionResponse>, Status> {
+        let token = auth::token(&request);
         let CreatePeerConnectionRequest {
             name,
             session_id,
             peer_connection_id,
+            idempotency_key,
         } = requester("create_peer_connection", request);
-        let pool = &get_session_attribute!(self, session_id.clone(), webrtc_pool);
-        let session = self.data.get_session(&session_id)?;
-        let peer_connection = pool.create_peer_connection_manager(peer_connection_id, name)?;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+        let pool = &session.webrtc_pool;
+
+        // a retried request returns the peer connection it created before
+        let reservation = match session.idempotency_keys.claim(idempotency_key)? {
+            Claim::Created(peer_connection_id) => {
+                let reply = CreatePeerConnectionResponse {
+                    session_id,
+                    peer_connection_id,
+                };
+                return responder("create_peer_connection", reply);
+            }
+            Claim::Reserved(reservation) => reservation,
+        };
+
+        let peer_connection_id = if peer_connection_id.is_empty() {
+            nanoid::nanoid!()
+        } else {
+            peer_connection_id
+        };
+        session.ensure_not_stopped()?;
+        session.ensure_peer_connection_id_available(&peer_connection_id)?;
+        let _room = self
+            .data
+            .reserve_peer_connections(&session, token.as_ref(), 1)?;
+        let mut peer_connection =
+            pool.create_peer_connection_manager(peer_connection_id.clone(), name)?;
+        peer_connection.watch_events();
 
         // add the peer connection to the session
         session.add_peer_connection(peer_connection)?;
-        let reply = webrtc::Empty {};
+        reservation.complete(peer_connection_id.clone());
+        let reply = CreatePeerConnectionResponse {
+            session_id,
+            peer_connection_id,
+        };
         responder("create_peer_connection", reply)
     }
 
+    async fn create_loopback_pair(
+        &self,
+        request: Request<CreateLoopbackPairRequest>,
+    ) -> Result<Response<CreateLoopbackPairResponse>, Status> {
+        let token = auth::token(&request);
+        let CreateLoopbackPairRequest {
+            session_id,
+            sender_peer_connection_id,
+            receiver_peer_connection_id,
+            name,
+            idempotency_key,
+        } = requester("create_loopback_pair", request);
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+
+        // a retried request returns the pair it created before
+        let reservation = match session.loopback_keys.claim(idempotency_key)? {
+            Claim::Created((sender_peer_connection_id, receiver_peer_connection_id)) => {
+                let reply = CreateLoopbackPairResponse {
+                    session_id,
+                    sender_peer_connection_id,
+                    receiver_peer_connection_id,
+                };
+                return responder("create_loopback_pair", reply);
+            }
+            Claim::Reserved(reservation) => reservation,
+        };
+
+        let sender_peer_connection_id = if sender_peer_connection_id.is_empty() {
+            nanoid::nanoid!()
+        } else {
+            sender_peer_connection_id
+        };
+        let receiver_peer_connection_id = if receiver_peer_connection_id.is_empty() {
+            nanoid::nanoid!()
+        } else {
+            receiver_peer_connection_id
+        };
+        let _room = self
+            .data
+            .reserve_peer_connections(&session, token.as_ref(), 2)?;
+        session
+            .value()
+            .create_loopback_pair(
+                sender_peer_connection_id.clone(),
+                receiver_peer_connection_id.clone(),
+                name,
+            )
+            .await?;
+        reservation.complete((
+            sender_peer_connection_id.clone(),
+            receiver_peer_connection_id.clone(),
+        ));
+
+        let reply = CreateLoopbackPairResponse {
+            session_id,
+            sender_peer_connection_id,
+            receiver_peer_connection_id,
+        };
+        responder("create_loopback_pair", reply)
+    }
+
     async fn create_offer(
         &self,
         request: Request<CreateSdpRequest>,
     ) -> Result<tonic::Response<CreateSdpResponse>, tonic::Status> {
+        let token = auth::token(&request);
         let request = requester("create_offer", request);
         let session_id = request.session_id;
         let peer_connection_id = request.peer_connection_id;
-        let session = self.data.get_session(&session_id)?;
-        let pc = session.value().get_peer_connection(&peer_connection_id)?;
-        let sdp = pc.value().create_offer().await?;
+        let transforms =
+            Transform::from_proto(request.transforms).context(&session_id, &peer_connection_id)?;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+        let pc = session
+            .value()
+            .get_peer_connection(&peer_connection_id)
+            .context(&session_id, &peer_connection_id)?;
+        let sdp = pc
+            .value()
+            .create_offer()
+            .await
+            .context(&session_id, &peer_connection_id)?;
+        let sdp = sdp_transform::apply(sdp.to_string(), &transforms)
+            .context(&session_id, &peer_connection_id)?;
 
         let reply = CreateSdpResponse {
-            sdp: sdp.to_string(),
+            sdp,
             sdp_type: webrtc::SdpType::Offer.into(),
             session_id,
             peer_connection_id,
@@ -212,16 +561,28 @@ impl WebRtc for SharedState {
         &self,
         request: Request<CreateSdpRequest>,
     ) -> Result<tonic::Response<CreateSdpResponse>, tonic::Status> {
+        let token = auth::token(&request);
         let request = requester("create_answer", request);
         let session_id = request.session_id;
         let peer_connection_id = request.peer_connection_id;
-        let session = self.data.get_session(&session_id)?;
-        let pc = session.value().get_peer_connection(&peer_connection_id)?;
+        let transforms =
+            Transform::from_proto(request.transforms).context(&session_id, &peer_connection_id)?;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+        let pc = session
+            .value()
+            .get_peer_connection(&peer_connection_id)
+            .context(&session_id, &peer_connection_id)?;
 
-        let sdp = pc.value().create_answer().await?;
+        let sdp = pc
+            .value()
+            .create_answer()
+            .await
+            .context(&session_id, &peer_connection_id)?;
+        let sdp = sdp_transform::apply(sdp.to_string(), &transforms)
+            .context(&session_id, &peer_connection_id)?;
 
         let reply = CreateSdpResponse {
-            sdp: sdp.to_string(),
+            sdp,
             sdp_type: webrtc::SdpType::Answer.into(),
             session_id,
             peer_connection_id,
@@ -234,15 +595,24 @@ impl WebRtc for SharedState {
         &self,
         request: Request<SetSdpRequest>,
     ) -> Result<tonic::Response<SetSdpResponse>, tonic::Status> {
+        let token = auth::token(&request);
         let request = requester("set_local_description", request);
         let sdp_type = request.sdp_type();
         let session_id = request.session_id;
         let peer_connection_id = request.peer_connection_id;
-        let session = self.data.get_session(&session_id)?;
-        let pc = session.value().get_peer_connection(&peer_connection_id)?;
+        let transforms =
+            Transform::from_proto(request.transforms).context(&session_id, &peer_connection_id)?;
+        let sdp = sdp_transform::apply(request.sdp, &transforms)
+            .context(&session_id, &peer_connection_id)?;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+        let pc = session
+            .value()
+            .get_peer_connection(&peer_connection_id)
+            .context(&session_id, &peer_connection_id)?;
         pc.value()
-            .set_local_description(sdp_type.into(), request.sdp)
-            .await?;
+            .set_local_description(sdp_type.into(), sdp)
+            .await
+            .context(&session_id, &peer_connection_id)?;
 
         let reply = SetSdpResponse {
             session_id,
@@ -256,16 +626,24 @@ impl WebRtc for SharedState {
         &self,
         request: Request<SetSdpRequest>,
     ) -> Result<tonic::Response<SetSdpResponse>, tonic::Status> {
+        let token = auth::token(&request);
         let request = requester("set_remote_description", request);
         let sdp_type = request.sdp_type();
-        let sdp = request.sdp;
         let session_id = request.session_id;
         let peer_connection_id = request.peer_connection_id;
-        let session = self.data.get_session(&session_id)?;
-        let pc = session.value().get_peer_connection(&peer_connection_id)?;
+        let transforms =
+            Transform::from_proto(request.transforms).context(&session_id, &peer_connection_id)?;
+        let sdp = sdp_transform::apply(request.sdp, &transforms)
+            .context(&session_id, &peer_connection_id)?;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+        let pc = session
+            .value()
+            .get_peer_connection(&peer_connection_id)
+            .context(&session_id, &peer_connection_id)?;
         pc.value()
             .set_remote_description(sdp_type.into(), sdp)
-            .await?;
+            .await
+            .context(&session_id, &peer_connection_id)?;
 
         let reply = SetSdpResponse {
             session_id,
@@ -280,22 +658,24 @@ impl WebRtc for SharedState {
         &self,
         request: tonic::Request<AddTrackRequest>,
     ) -> Result<tonic::Response<Empty>, tonic::Status> {
+        let token = auth::token(&request);
         let request = requester("add_track", request);
-        println!("got this far: {:?}", &request);
         let session_id = request.session_id;
         let peer_connection_id = request.peer_connection_id;
         let _track_id = request.track_id;
         let track_label = request.track_label;
-        println!("data: {:?} {:?}", &session_id, self.data);
-        let session = self.data.get_session(&session_id)?;
-        let pc = session.value().get_peer_connection(&peer_connection_id)?;
-        println!("got past here?");
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+        let pc = session
+            .value()
+            .get_peer_connection(&peer_connection_id)
+            .context(&session_id, &peer_connection_id)?;
         let video_source = &session.value().video_source;
         let pool = &session.value().webrtc_pool;
 
         pc.value()
             .add_track(pool, video_source, track_label)
-            .await?;
+            .await
+            .context(&session_id, &peer_connection_id)?;
 
         let reply = Empty {};
 
@@ -306,11 +686,15 @@ impl WebRtc for SharedState {
         &self,
         request: tonic::Request<AddTransceiverRequest>,
     ) -> Result<tonic::Response<Empty>, tonic::Status> {
+        let token = auth::token(&request);
         let request = requester("add_transceiver", request);
         let session_id = request.session_id;
         let peer_connection_id = request.peer_connection_id;
-        let session = self.data.get_session(&session_id)?;
-        let pc = session.value().get_peer_connection(&peer_connection_id)?;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
+        let pc = session
+            .value()
+            .get_peer_connection(&peer_connection_id)
+            .context(&session_id, &peer_connection_id)?;
         let _track_id = request.track_id;
         let track_label = if request.track_label.is_empty() {
             nanoid::nanoid!()
@@ -321,7 +705,8 @@ impl WebRtc for SharedState {
         let video_source = &session.value().video_source;
         pc.value()
             .add_transceiver(pool, video_source, track_label)
-            .await?;
+            .await
+            .context(&session_id, &peer_connection_id)?;
         let reply = Empty {};
 
         responder("add_transceiver", reply)
@@ -331,15 +716,16 @@ impl WebRtc for SharedState {
         &self,
         request: tonic::Request<webrtc::ObserverRequest>,
     ) -> Result<tonic::Response<ObserverStream>, tonic::Status> {
+        let token = auth::token(&request);
         let request = requester("observer", request);
         let session_id = request.session_id;
         let peer_connection_id = request.peer_connection_id;
-        let session = self.data.get_session(&session_id)?;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
         let mut pc = session
             .value()
             .peer_connections
             .get_mut(&peer_connection_id)
-            .ok_or_else(|| tonic::Status::new(tonic::Code::NotFound, "PeerConnection not found"))?;
+            .ok_or_else(|| ServerError::InvalidPeerConnection(peer_connection_id.clone()))?;
 
         let mut track_rx = pc.value_mut().video_track_rx()?;
         let mut ice_rx = pc.value_mut().ice_candidates_rx()?;
@@ -398,15 +784,16 @@ impl WebRtc for SharedState {
         &self,
         request: tonic::Request<webrtc::GetTransceiversRequest>,
     ) -> Result<tonic::Response<webrtc::GetTransceiversResponse>, tonic::Status> {
+        let token = auth::token(&request);
         let request = requester("observer", request);
         let session_id = request.session_id;
         let peer_connection_id = request.peer_connection_id;
-        let session = self.data.get_session(&session_id)?;
+        let session = self.data.get_owned_session(&session_id, token.as_ref())?;
         let pc = session
             .value()
             .peer_connections
             .get(&peer_connection_id)
-            .ok_or_else(|| tonic::Status::new(tonic::Code::NotFound, "PeerConnection not found"))?;
+            .ok_or_else(|| ServerError::InvalidPeerConnection(peer_connection_id.clone()))?;
         let (video, audio) = pc.get_transceivers().await;
         let mut result = vec![];
         video.into_iter().for_each(|t| {
@@ -436,6 +823,51 @@ impl WebRtc for SharedState {
         };
         responder("get_transceivers", reply)
     }
+
+    async fn update_server_config(
+        &self,
+        request: Request<UpdateServerConfigRequest>,
+    ) -> Result<Response<UpdateServerConfigResponse>, Status> {
+        let token = auth::token(&request);
+        let request = requester("update_server_config", request);
+        auth::ensure_admin(token.as_ref())?;
+        let runtime_config = runtime_config::update(request)?;
+        let reply = UpdateServerConfigResponse {
+            config: Some(runtime_config.into()),
+        };
+
+        responder("update_server_config", reply)
+    }
+
+    type StreamLogsStream = LogStream;
+
+    async fn stream_logs(
+        &self,
+        request: Request<StreamLogsRequest>,
+    ) -> Result<Response<LogStream>, Status> {
+        let token = auth::token(&request);
+        let StreamLogsRequest { session_id } = requester("stream_logs", request);
+        let mut logs = self
+            .data
+            .get_owned_session(&session_id, token.as_ref())?
+            .value()
+            .logs
+            .subscribe();
+
+        let stream_out = stream! {
+            loop {
+                match logs.recv().await {
+                    Ok(line) => yield Ok(line.into()),
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Skipped {} log lines of session {}", skipped, session_id);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(stream_out) as LogStream))
+    }
 }
 
 #[cfg(test)]
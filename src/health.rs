@@ -0,0 +1,180 @@
+//! The standard `grpc.health.v1.Health` service.
+//!
+//! The server and the `webrtc.WebRtc` service are serving only while the
+//! server can take more load, so orchestrators can gate traffic on it.
+
+use crate::capacity::Capacity;
+use crate::data::Data;
+use crate::metrics;
+use crate::runtime_config;
+use crate::webrtc_pool::WebRTCPool;
+use async_stream::stream;
+use futures::Stream;
+use proto::health_check_response::ServingStatus;
+use proto::health_server::Health;
+use proto::{HealthCheckRequest, HealthCheckResponse};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info};
+
+pub(crate) mod proto {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+pub(crate) const WEBRTC_SERVICE: &str = "webrtc.WebRtc";
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send>>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct HealthService {
+    data: Arc<Data>,
+    factories_ready: Arc<AtomicBool>,
+}
+
+impl HealthService {
+    /// The service isn't ready until a `WebRTCPool` has been created, which
+    /// is probed in the background.
+    pub(crate) fn new(data: Arc<Data>) -> Self {
+        let factories_ready = Arc::new(AtomicBool::new(false));
+        let ready = factories_ready.clone();
+
+        tokio::task::spawn_blocking(move || match WebRTCPool::new(1) {
+            Ok(_) => {
+                info!("Initialized the WebRTC factories");
+                ready.store(true, Ordering::Relaxed);
+            }
+            Err(e) => error!("Could not initialize the WebRTC factories: {}", e),
+        });
+
+        Self {
+            data,
+            factories_ready,
+        }
+    }
+
+    /// Why the server can't take more load, if it can't
+    async fn unready_reason(&self) -> Option<String> {
+        if !self.factories_ready.load(Ordering::Relaxed) {
+            return Some("the WebRTC factories are not initialized".into());
+        }
+
+        if !metrics::statsd_resolves().await {
+            return Some(format!(
+                "statsd host {} does not resolve",
+                runtime_config::get().statsd_host
+            ));
+        }
+
+        if let Err(e) = Capacity::of(&self.data).ensure_room() {
+            return Some(e.to_string());
+        }
+
+        None
+    }
+
+    async fn status(&self, service: &str) -> ServingStatus {
+        if !service.is_empty() && service != WEBRTC_SERVICE {
+            return ServingStatus::ServiceUnknown;
+        }
+
+        match self.unready_reason().await {
+            Some(reason) => {
+                debug!("Not serving: {}", reason);
+                ServingStatus::NotServing
+            }
+            None => ServingStatus::Serving,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+    type WatchStream = WatchStream;
+
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+
+        match self.status(&service).await {
+            ServingStatus::ServiceUnknown => Err(Status::not_found(format!(
+                "Service {} does not exist",
+                service
+            ))),
+            status => Ok(Response::new(HealthCheckResponse {
+                status: status as i32,
+            })),
+        }
+    }
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let health = self.clone();
+
+        // send the current status, then every change of it
+        let stream_out = stream! {
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            let mut last_status = None;
+
+            loop {
+                interval.tick().await;
+                let status = health.status(&service).await;
+
+                if last_status != Some(status) {
+                    last_status = Some(status);
+                    yield Ok(HealthCheckResponse { status: status as i32 });
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(stream_out) as Self::WatchStream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health_service() -> HealthService {
+        HealthService {
+            data: Arc::new(Data::new()),
+            factories_ready: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_is_not_serving_until_the_factories_are_initialized() {
+        let health = health_service();
+
+        assert_eq!(ServingStatus::NotServing, health.status("").await);
+        assert_eq!(
+            ServingStatus::NotServing,
+            health.status(WEBRTC_SERVICE).await
+        );
+    }
+
+    #[tokio::test]
+    async fn it_rejects_an_unknown_service() {
+        let health = health_service();
+        let request = Request::new(HealthCheckRequest {
+            service: "unknown".into(),
+        });
+
+        assert_eq!(
+            ServingStatus::ServiceUnknown,
+            health.status("unknown").await
+        );
+        assert_eq!(
+            tonic::Code::NotFound,
+            health.check(request).await.unwrap_err().code()
+        );
+    }
+}
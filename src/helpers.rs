@@ -22,3 +22,13 @@ pub(crate) fn elapsed(
 pub(crate) fn systemtime_to_timestamp(time: Option<SystemTime>) -> Option<Timestamp> {
     time.map(Timestamp::from)
 }
+
+// the nearest-rank percentile of sorted values
+pub(crate) fn percentile<T: Copy>(sorted: &[T], percent: f64) -> Option<T> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len()) - 1).copied()
+}
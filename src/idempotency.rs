@@ -0,0 +1,166 @@
+use crate::error::{Result, ServerError};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+/// How long a retried create request returns the id it created before.
+pub(crate) const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
+struct Key<T> {
+    // None while the create request holding the key is in progress
+    id: Option<T>,
+    at: Instant,
+}
+
+/// The ids created by requests, keyed by the idempotency key of the request.
+#[derive(Debug)]
+pub(crate) struct IdempotencyKeys<T = String> {
+    keys: DashMap<String, Key<T>>,
+    ttl: Duration,
+}
+
+/// The outcome of claiming an idempotency key.
+pub(crate) enum Claim<'a, T = String> {
+    /// A previous request with the key created this id.
+    Created(T),
+    /// The key is held by this request until the reservation is completed or dropped.
+    Reserved(Reservation<'a, T>),
+}
+
+/// An idempotency key held by an in-progress create request, released on drop.
+pub(crate) struct Reservation<'a, T = String> {
+    keys: &'a IdempotencyKeys<T>,
+    key: Option<String>,
+}
+
+impl<T: Clone> Default for IdempotencyKeys<T> {
+    fn default() -> Self {
+        Self::with_ttl(IDEMPOTENCY_KEY_TTL)
+    }
+}
+
+impl<T: Clone> IdempotencyKeys<T> {
+    pub(crate) fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            keys: DashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Reserve the key for a create request, or return the id a previous request created.
+    ///
+    /// An empty key reserves nothing.  A key held by a request that is still in
+    /// progress fails, so concurrent retries can't create twice.
+    pub(crate) fn claim(&self, key: String) -> Result<Claim<'_, T>> {
+        if key.is_empty() {
+            return Ok(Claim::Reserved(Reservation {
+                keys: self,
+                key: None,
+            }));
+        }
+
+        let now = Instant::now();
+        self.keys
+            .retain(|_, key| key.id.is_none() || now.duration_since(key.at) < self.ttl);
+
+        match self.keys.entry(key) {
+            Entry::Occupied(entry) => match &entry.get().id {
+                Some(id) => Ok(Claim::Created(id.clone())),
+                None => Err(ServerError::IdempotencyKeyInUse(entry.key().clone())),
+            },
+            Entry::Vacant(entry) => {
+                let key = entry.key().clone();
+                entry.insert(Key { id: None, at: now });
+
+                Ok(Claim::Reserved(Reservation {
+                    keys: self,
+                    key: Some(key),
+                }))
+            }
+        }
+    }
+}
+
+impl<T> Reservation<'_, T> {
+    /// Keep the key, mapped to the id the request created.
+    pub(crate) fn complete(mut self, id: T) {
+        if let Some(key) = self.key.take() {
+            self.keys.keys.insert(
+                key,
+                Key {
+                    id: Some(id),
+                    at: Instant::now(),
+                },
+            );
+        }
+    }
+}
+
+impl<T> Drop for Reservation<'_, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.keys.keys.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserve<'a>(keys: &'a IdempotencyKeys, key: &str) -> Reservation<'a> {
+        match keys.claim(key.into()).unwrap() {
+            Claim::Reserved(reservation) => reservation,
+            Claim::Created(id) => panic!("{} was already created", id),
+        }
+    }
+
+    #[test]
+    fn it_returns_the_id_of_a_completed_key() {
+        let keys = IdempotencyKeys::default();
+        reserve(&keys, "key").complete("id".into());
+
+        assert!(matches!(keys.claim("key".into()), Ok(Claim::Created(id)) if id == "id"));
+        reserve(&keys, "").complete("other".into());
+        reserve(&keys, "");
+    }
+
+    #[test]
+    fn it_rejects_a_key_in_progress() {
+        let keys = IdempotencyKeys::default();
+        let reservation = reserve(&keys, "key");
+
+        assert!(matches!(
+            keys.claim("key".into()),
+            Err(ServerError::IdempotencyKeyInUse(_))
+        ));
+
+        // a failed request releases the key for a retry
+        drop(reservation);
+        reserve(&keys, "key");
+    }
+
+    #[test]
+    fn it_returns_the_ids_of_a_pair() {
+        let keys: IdempotencyKeys<(String, String)> = IdempotencyKeys::default();
+
+        match keys.claim("key".into()).unwrap() {
+            Claim::Reserved(reservation) => reservation.complete(("a".into(), "b".into())),
+            Claim::Created(_) => panic!("the key was already created"),
+        }
+
+        assert!(matches!(
+            keys.claim("key".into()),
+            Ok(Claim::Created((a, b))) if a == "a" && b == "b"
+        ));
+    }
+
+    #[test]
+    fn it_evicts_expired_keys() {
+        let keys = IdempotencyKeys::with_ttl(Duration::from_millis(0));
+        reserve(&keys, "key").complete("id".into());
+
+        reserve(&keys, "key");
+    }
+}
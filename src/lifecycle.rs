@@ -0,0 +1,188 @@
+//! Milestones of setting up a peer connection, timed from its creation.
+
+use crate::helpers::percentile;
+use crate::server::webrtc;
+use parking_lot::Mutex;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, strum::ToString)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum Milestone {
+    OfferCreated,
+    RemoteDescriptionSet,
+    FirstIceCandidate,
+    /// ICE and DTLS connected.  The bindings only observe the combined
+    /// connection state, not the ICE and DTLS transport states on their own.
+    Connected,
+    FirstPacketSent,
+    FirstFrameDecoded,
+}
+
+#[derive(Debug, Default)]
+struct Reached {
+    // since creation, the first time each milestone was reached
+    durations: BTreeMap<Milestone, Duration>,
+    // milestones that weren't written to the metrics yet
+    unexported: Vec<Milestone>,
+}
+
+/// Shared by a peer connection and whatever observes it, so milestones can be
+/// marked without holding a guard on the peer connection.
+#[derive(Debug)]
+pub(crate) struct Lifecycle {
+    created: Instant,
+    reached: Mutex<Reached>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self {
+            created: Instant::now(),
+            reached: Mutex::new(Reached::default()),
+        }
+    }
+}
+
+impl Lifecycle {
+    /// Record the first time a milestone is reached, later ones are ignored.
+    ///
+    /// Returns whether this was the first time.
+    pub(crate) fn mark(&self, milestone: Milestone) -> bool {
+        let reached = &mut *self.reached.lock();
+
+        match reached.durations.entry(milestone) {
+            Entry::Vacant(entry) => {
+                let duration = entry.insert(self.created.elapsed());
+                debug!("reached {} after {:?}", milestone.to_string(), duration);
+                reached.unexported.push(milestone);
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    pub(crate) fn reached(&self, milestone: Milestone) -> bool {
+        self.reached.lock().durations.contains_key(&milestone)
+    }
+
+    pub(crate) fn durations(&self) -> BTreeMap<Milestone, Duration> {
+        self.reached.lock().durations.clone()
+    }
+
+    /// The milestones reached since the last call, to write to the metrics once
+    pub(crate) fn take_unexported(&self) -> Vec<(Milestone, Duration)> {
+        let mut reached = self.reached.lock();
+        let unexported = std::mem::take(&mut reached.unexported);
+
+        unexported
+            .into_iter()
+            .map(|milestone| (milestone, reached.durations[&milestone]))
+            .collect()
+    }
+}
+
+/// The distribution of the time to a milestone across peer connections.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SetupLatency {
+    pub(crate) milestone: Milestone,
+    pub(crate) count: usize,
+    pub(crate) min: Duration,
+    pub(crate) p50: Duration,
+    pub(crate) p95: Duration,
+    pub(crate) max: Duration,
+}
+
+/// Summarize each milestone reached by at least one of the lifecycles.
+pub(crate) fn setup_latencies<'a>(
+    lifecycles: impl Iterator<Item = &'a Lifecycle>,
+) -> Vec<SetupLatency> {
+    let mut by_milestone: BTreeMap<Milestone, Vec<Duration>> = BTreeMap::new();
+
+    for lifecycle in lifecycles {
+        for (milestone, duration) in lifecycle.durations() {
+            by_milestone.entry(milestone).or_default().push(duration);
+        }
+    }
+
+    by_milestone
+        .into_iter()
+        .map(|(milestone, mut durations)| {
+            durations.sort();
+
+            SetupLatency {
+                milestone,
+                count: durations.len(),
+                min: durations[0],
+                p50: percentile(&durations, 50.0).unwrap_or_default(),
+                p95: percentile(&durations, 95.0).unwrap_or_default(),
+                max: durations[durations.len() - 1],
+            }
+        })
+        .collect()
+}
+
+impl From<SetupLatency> for webrtc::SetupLatency {
+    fn from(setup_latency: SetupLatency) -> Self {
+        Self {
+            milestone: setup_latency.milestone.to_string(),
+            count: setup_latency.count as u32,
+            min_ms: setup_latency.min.as_millis() as u64,
+            p50_ms: setup_latency.p50.as_millis() as u64,
+            p95_ms: setup_latency.p95.as_millis() as u64,
+            max_ms: setup_latency.max.as_millis() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_marks_a_milestone_once() {
+        let lifecycle = Lifecycle::default();
+        assert!(!lifecycle.reached(Milestone::OfferCreated));
+        assert!(lifecycle.mark(Milestone::OfferCreated));
+        assert!(lifecycle.reached(Milestone::OfferCreated));
+        let first = lifecycle.durations()[&Milestone::OfferCreated];
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!lifecycle.mark(Milestone::OfferCreated));
+
+        assert_eq!(first, lifecycle.durations()[&Milestone::OfferCreated]);
+        assert_eq!(1, lifecycle.take_unexported().len());
+        assert!(lifecycle.take_unexported().is_empty());
+    }
+
+    #[test]
+    fn it_summarizes_setup_latencies() {
+        let lifecycles: Vec<Lifecycle> = (1..=20)
+            .map(|ms| {
+                let lifecycle = Lifecycle::default();
+                lifecycle
+                    .reached
+                    .lock()
+                    .durations
+                    .insert(Milestone::Connected, Duration::from_millis(ms * 10));
+                lifecycle
+            })
+            .collect();
+        lifecycles[0].mark(Milestone::OfferCreated);
+
+        let latencies = setup_latencies(lifecycles.iter());
+        let connected = latencies
+            .iter()
+            .find(|latency| latency.milestone == Milestone::Connected)
+            .unwrap();
+
+        assert_eq!(2, latencies.len());
+        assert_eq!(20, connected.count);
+        assert_eq!(Duration::from_millis(10), connected.min);
+        assert_eq!(Duration::from_millis(100), connected.p50);
+        assert_eq!(Duration::from_millis(190), connected.p95);
+        assert_eq!(Duration::from_millis(200), connected.max);
+    }
+}
@@ -1,7 +1,86 @@
+use crate::config::{Config, LogFormat};
+use crate::error::{Result, ServerError};
+use crate::runtime_config;
 use crate::server::webrtc;
 use libwebrtc_sys::ffi::{set_arcas_log_level, LoggingSeverity};
+use once_cell::sync::OnceCell;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+use tracing::{Level, Subscriber};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
 
-#[derive(Debug)]
+const SERVICE_NAME: &str = "load-test-server";
+
+static LOG_FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+/// Log in the configured format, filtered by `RUST_LOG` until
+/// `set_log_filter` is called, and export spans to the OTLP endpoint if
+/// one is configured.
+pub(crate) fn init_logger(config: &Config) -> Result<()> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+    let otlp = config
+        .otlp_endpoint
+        .as_deref()
+        .map(otlp_layer)
+        .transpose()?;
+    let registry = tracing_subscriber::registry().with(filter).with(otlp);
+
+    match config.log_format {
+        LogFormat::Pretty => registry.with(fmt::layer()).init(),
+        LogFormat::Json => registry.with(fmt::layer().json()).init(),
+    }
+
+    LOG_FILTER.set(handle).ok();
+
+    Ok(())
+}
+
+fn otlp_layer<S>(endpoint: &str) -> Result<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint);
+    let resource = Resource::new(vec![KeyValue::new("service.name", SERVICE_NAME)]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(opentelemetry::runtime::Tokio)
+        .map_err(|e| ServerError::InvalidConfig(format!("OTLP exporter: {}", e)))?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Export the spans that haven't been exported yet.
+pub(crate) fn shutdown_logger() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Replace the filter of the logger, in the `RUST_LOG` syntax.
+pub(crate) fn parse_log_filter(filter: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(filter)
+        .map_err(|e| ServerError::InvalidConfigUpdate(format!("invalid log filter: {}", e)))
+}
+
+pub(crate) fn set_log_filter(filter: EnvFilter) -> Result<()> {
+    match LOG_FILTER.get() {
+        Some(handle) => handle
+            .reload(filter)
+            .map_err(|e| ServerError::InternalError(e.to_string())),
+        // e.g. in tests, where there's no logger
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum LogLevel {
     None,
     Info,
@@ -20,6 +99,40 @@ impl LogLevel {
     pub(crate) fn set_log_level(log_level: &LogLevel) {
         set_arcas_log_level(log_level.into());
     }
+
+    fn verbosity(&self) -> u8 {
+        match self {
+            LogLevel::None => 0,
+            LogLevel::Error => 1,
+            LogLevel::Warn => 2,
+            LogLevel::Info => 3,
+            LogLevel::Verbose => 4,
+        }
+    }
+
+    /// Whether a line at `level` is logged at this log level
+    pub(crate) fn allows(&self, level: Level) -> bool {
+        LogLevel::from(level).verbosity() <= self.verbosity()
+    }
+
+    /// Apply the libwebrtc log level of the server, which `UpdateServerConfig` can change.
+    ///
+    /// libwebrtc only has a process-wide log level and writes its lines to
+    /// stderr, so the log level of a session only filters its session logs.
+    pub(crate) fn reset_log_level() {
+        Self::set_log_level(&runtime_config::get().log_level);
+    }
+}
+
+impl From<Level> for LogLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::ERROR => LogLevel::Error,
+            Level::WARN => LogLevel::Warn,
+            Level::INFO => LogLevel::Info,
+            _ => LogLevel::Verbose,
+        }
+    }
 }
 
 impl Into<LoggingSeverity> for &LogLevel {
@@ -46,3 +159,129 @@ impl From<i32> for LogLevel {
         }
     }
 }
+
+impl From<LogLevel> for webrtc::LogLevel {
+    fn from(log_level: LogLevel) -> Self {
+        match log_level {
+            LogLevel::None => webrtc::LogLevel::None,
+            LogLevel::Info => webrtc::LogLevel::Info,
+            LogLevel::Warn => webrtc::LogLevel::Warn,
+            LogLevel::Error => webrtc::LogLevel::Error,
+            LogLevel::Verbose => webrtc::LogLevel::Verbose,
+        }
+    }
+}
+
+// lines aren't kept for slow subscribers beyond this
+const LOG_CAPACITY: usize = 1024;
+
+/// A log line of a session.
+#[derive(Debug, Clone)]
+pub(crate) struct LogLine {
+    pub(crate) time: SystemTime,
+    pub(crate) level: Level,
+    pub(crate) target: String,
+    pub(crate) session_id: String,
+    pub(crate) peer_connection_id: Option<String>,
+    pub(crate) message: String,
+}
+
+impl From<LogLine> for webrtc::LogLine {
+    fn from(line: LogLine) -> Self {
+        webrtc::LogLine {
+            time: Some(line.time.into()),
+            level: webrtc::LogLevel::from(LogLevel::from(line.level)) as i32,
+            target: line.target,
+            session_id: line.session_id,
+            peer_connection_id: line.peer_connection_id.unwrap_or_default(),
+            message: line.message,
+        }
+    }
+}
+
+/// The log sink of a session, which streams the lines allowed by the log
+/// level of the session to `StreamLogs` subscribers.
+pub(crate) struct SessionLogs {
+    session_id: String,
+    log_level: LogLevel,
+    sender: broadcast::Sender<LogLine>,
+}
+
+impl SessionLogs {
+    pub(crate) fn new(session_id: String, log_level: LogLevel) -> Self {
+        let (sender, _) = broadcast::channel(LOG_CAPACITY);
+
+        Self {
+            session_id,
+            log_level,
+            sender,
+        }
+    }
+
+    /// Stream a line, tagged with the peer connection it's about, use
+    /// `session_log!` to also log it.
+    pub(crate) fn publish(
+        &self,
+        target: &str,
+        level: Level,
+        peer_connection_id: Option<&str>,
+        args: std::fmt::Arguments,
+    ) {
+        // only format the line when someone is listening
+        if self.sender.receiver_count() == 0 || !self.log_level.allows(level) {
+            return;
+        }
+
+        let line = LogLine {
+            time: SystemTime::now(),
+            level,
+            target: target.into(),
+            session_id: self.session_id.clone(),
+            peer_connection_id: peer_connection_id.map(Into::into),
+            message: args.to_string(),
+        };
+
+        self.sender.send(line).ok();
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<LogLine> {
+        self.sender.subscribe()
+    }
+}
+
+/// Log a line of a session, optionally about one of its peer connections,
+/// which is also streamed to the subscribers of the session logs.
+#[macro_export]
+macro_rules! session_log {
+    ($logs:expr, $level:expr, $peer_connection_id:expr, $($arg:tt)+) => {{
+        tracing::event!($level, $($arg)+);
+        $logs.publish(module_path!(), $level, $peer_connection_id, format_args!($($arg)+));
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_streams_lines_allowed_by_the_session_log_level() {
+        let logs = SessionLogs::new("session".into(), LogLevel::Warn);
+        let mut receiver = logs.subscribe();
+
+        session_log!(logs, Level::INFO, None, "not streamed");
+        session_log!(logs, Level::WARN, Some("peer connection"), "streamed {}", 1);
+
+        let line = receiver.try_recv().unwrap();
+        assert_eq!(line.message, "streamed 1");
+        assert_eq!(line.peer_connection_id, Some("peer connection".into()));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn it_allows_lines_up_to_the_log_level() {
+        assert!(LogLevel::Verbose.allows(Level::DEBUG));
+        assert!(LogLevel::Warn.allows(Level::ERROR));
+        assert!(!LogLevel::Warn.allows(Level::INFO));
+        assert!(!LogLevel::None.allows(Level::ERROR));
+    }
+}
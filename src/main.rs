@@ -1,13 +1,26 @@
+mod auth;
+mod capacity;
+mod collector;
 mod config;
 mod data;
 mod error;
 mod handlers;
+mod health;
 mod helpers;
+mod idempotency;
+mod lifecycle;
 mod log;
+mod media_detection;
 mod metrics;
 mod peer_connection;
+mod report;
+mod rest;
+mod runtime_config;
+mod sdp_transform;
 pub(crate) mod server;
 mod session;
+mod shutdown;
+mod slo;
 mod stats;
 pub mod webrtc_pool;
 
@@ -17,20 +30,56 @@ use crate::error::Result;
 use crate::log::LogLevel;
 use crate::server::serve;
 use std::sync::Arc;
+use tokio::sync::watch;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    pretty_env_logger::init();
-    LogLevel::set_log_level(&LogLevel::default());
+    config::init()?;
+
+    crate::log::init_logger(&CONFIG)?;
+    LogLevel::reset_log_level();
 
     let shared_state = SharedState {
         data: Arc::from(Data::new()),
     };
 
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
     // start exporting stats
     shared_state.start_metrics_collection();
+    capacity::start_resource_sampling(shutdown_rx.clone());
+
+    let servers = serve_all(shared_state.clone(), shutdown_rx);
+    tokio::pin!(servers);
+
+    let result = tokio::select! {
+        result = &mut servers => result,
+        result = shutdown::signal_received() => match result {
+            Ok(()) => shutdown::drain(&shared_state, shutdown_tx, servers).await,
+            Err(e) => Err(e),
+        }
+    };
 
-    // run the gRPC server
+    crate::log::shutdown_logger();
+
+    result
+}
+
+/// Run the gRPC server, and the REST gateway if a port is configured
+async fn serve_all(shared_state: SharedState, shutdown_rx: watch::Receiver<bool>) -> Result<()> {
     let addr = format!("{}:{}", CONFIG.host, CONFIG.port);
-    serve(&addr, shared_state).await
+    let grpc_shutdown = shutdown::requested(shutdown_rx.clone());
+
+    match &CONFIG.rest_port {
+        Some(rest_port) => {
+            let rest_addr = format!("{}:{}", CONFIG.host, rest_port);
+            let rest_shutdown = shutdown::requested(shutdown_rx);
+            tokio::try_join!(
+                serve(&addr, shared_state.clone(), grpc_shutdown),
+                rest::serve(&rest_addr, shared_state, rest_shutdown)
+            )?;
+            Ok(())
+        }
+        None => serve(&addr, shared_state, grpc_shutdown).await,
+    }
 }
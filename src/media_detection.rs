@@ -0,0 +1,196 @@
+//! Decides whether a peer connection is sending or receiving media from the
+//! byte and frame counters of successive stats polls.
+
+use crate::server::webrtc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The rules of a session for marking media as flowing or stalled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct DetectionRules {
+    /// The bitrate between polls needed to count as flowing, any growth when 0
+    pub(crate) min_bitrate_kbps: u32,
+    /// Polls in a row that must disagree with the current state to change it
+    pub(crate) consecutive_samples: u32,
+    /// Also require frames to be encoded (sending) or decoded (receiving)
+    pub(crate) require_frames: bool,
+}
+
+impl Default for DetectionRules {
+    fn default() -> Self {
+        Self {
+            min_bitrate_kbps: 0,
+            consecutive_samples: 1,
+            require_frames: false,
+        }
+    }
+}
+
+impl From<webrtc::MediaDetection> for DetectionRules {
+    fn from(media_detection: webrtc::MediaDetection) -> Self {
+        Self {
+            min_bitrate_kbps: media_detection.min_bitrate_kbps,
+            consecutive_samples: media_detection.consecutive_samples.max(1),
+            require_frames: media_detection.require_frames,
+        }
+    }
+}
+
+/// The flow of media in one direction of a peer connection.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MediaFlow {
+    pub(crate) active: bool,
+    /// From the creation of the peer connection to the first time media flowed
+    pub(crate) time_to_first_media: Option<Duration>,
+    /// Times media stopped flowing after it had started
+    pub(crate) stalls: u32,
+    pub(crate) last_transition: Option<SystemTime>,
+    created: Instant,
+    last_sample: Instant,
+    last_bytes: u64,
+    last_frames: u64,
+    // polls in a row that disagreed with `active`
+    streak: u32,
+}
+
+impl MediaFlow {
+    pub(crate) fn new(created: Instant) -> Self {
+        Self {
+            active: false,
+            time_to_first_media: None,
+            stalls: 0,
+            last_transition: None,
+            created,
+            last_sample: created,
+            last_bytes: 0,
+            last_frames: 0,
+            streak: 0,
+        }
+    }
+
+    /// Record the counters of a poll, returning whether media is flowing.
+    pub(crate) fn sample(
+        &mut self,
+        rules: &DetectionRules,
+        bytes: u64,
+        frames: u64,
+        now: Instant,
+    ) -> bool {
+        let bytes_delta = bytes.saturating_sub(self.last_bytes);
+        let frames_delta = frames.saturating_sub(self.last_frames);
+        let seconds = now.duration_since(self.last_sample).as_secs_f64();
+        let kbps = if seconds > 0.0 {
+            (bytes_delta * 8) as f64 / seconds / 1000.0
+        } else {
+            0.0
+        };
+
+        let flowing = bytes_delta > 0
+            && kbps >= rules.min_bitrate_kbps as f64
+            && (!rules.require_frames || frames_delta > 0);
+
+        self.last_sample = now;
+        self.last_bytes = bytes;
+        self.last_frames = frames;
+
+        if flowing == self.active {
+            self.streak = 0;
+        } else {
+            self.streak += 1;
+
+            if self.streak >= rules.consecutive_samples.max(1) {
+                self.transition(flowing, now);
+            }
+        }
+
+        self.active
+    }
+
+    fn transition(&mut self, active: bool, now: Instant) {
+        self.active = active;
+        self.streak = 0;
+        self.last_transition = Some(SystemTime::now());
+
+        if active && self.time_to_first_media.is_none() {
+            self.time_to_first_media = Some(now.duration_since(self.created));
+        }
+
+        if !active {
+            self.stalls += 1;
+        }
+    }
+}
+
+impl From<&MediaFlow> for webrtc::MediaFlow {
+    fn from(media_flow: &MediaFlow) -> Self {
+        Self {
+            active: media_flow.active,
+            time_to_first_media_ms: media_flow
+                .time_to_first_media
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or(0),
+            stalls: media_flow.stalls,
+            last_transition: media_flow.last_transition.map(Into::into),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLL: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn it_detects_flowing_media_on_any_growth_by_default() {
+        let created = Instant::now();
+        let rules = DetectionRules::default();
+        let mut flow = MediaFlow::new(created);
+
+        assert!(!flow.sample(&rules, 0, 0, created + POLL));
+        assert!(flow.sample(&rules, 100, 0, created + POLL * 2));
+        assert_eq!(Some(POLL * 2), flow.time_to_first_media);
+
+        assert!(!flow.sample(&rules, 100, 0, created + POLL * 3));
+        assert_eq!(1, flow.stalls);
+    }
+
+    #[test]
+    fn it_waits_for_consecutive_samples() {
+        let created = Instant::now();
+        let rules = DetectionRules {
+            consecutive_samples: 2,
+            ..Default::default()
+        };
+        let mut flow = MediaFlow::new(created);
+
+        assert!(!flow.sample(&rules, 100, 0, created + POLL));
+        assert!(flow.sample(&rules, 200, 0, created + POLL * 2));
+
+        // a single stalled poll doesn't flip the state
+        assert!(flow.sample(&rules, 200, 0, created + POLL * 3));
+        assert!(flow.sample(&rules, 300, 0, created + POLL * 4));
+        assert_eq!(0, flow.stalls);
+
+        assert!(flow.sample(&rules, 300, 0, created + POLL * 5));
+        assert!(!flow.sample(&rules, 300, 0, created + POLL * 6));
+        assert_eq!(1, flow.stalls);
+        assert!(flow.last_transition.is_some());
+    }
+
+    #[test]
+    fn it_applies_the_bitrate_and_frame_rules() {
+        let created = Instant::now();
+        let rules = DetectionRules {
+            min_bitrate_kbps: 100,
+            require_frames: true,
+            ..Default::default()
+        };
+        let mut flow = MediaFlow::new(created);
+
+        // 80 kbps
+        assert!(!flow.sample(&rules, 10_000, 30, created + POLL));
+        // 160 kbps without new frames
+        assert!(!flow.sample(&rules, 30_000, 30, created + POLL * 2));
+        assert!(flow.sample(&rules, 50_000, 60, created + POLL * 3));
+    }
+}
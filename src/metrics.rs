@@ -1,16 +1,76 @@
-use crate::config::CONFIG;
+use crate::collector::Tick;
+use crate::config::{MetricsBackend, CONFIG};
+use crate::error::{Result, ServerError};
+use crate::lifecycle::Milestone;
+use crate::runtime_config;
+use crate::stats::QualityLimitation;
 
 use lazy_static::lazy_static;
 use libwebrtc_sys::ffi::{ArcasVideoReceiverStats, ArcasVideoSenderStats};
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
 
 lazy_static! {
-    static ref METRICS: dogstatsd::Client = {
-        let opts = dogstatsd::Options {
-            to_addr: format!("{}:{}", CONFIG.statsd_host, CONFIG.statsd_port),
-            ..Default::default()
-        };
-        dogstatsd::Client::new(opts).unwrap()
+    static ref METRICS: RwLock<Arc<dogstatsd::Client>> = RwLock::new(Arc::new(
+        statsd_client(&CONFIG.statsd_host, CONFIG.statsd_port).unwrap()
+    ));
+}
+
+pub(crate) fn statsd_client(host: &str, port: u16) -> Result<dogstatsd::Client> {
+    let opts = dogstatsd::Options {
+        to_addr: format!("{}:{}", host, port),
+        ..Default::default()
     };
+
+    dogstatsd::Client::new(opts).map_err(|e| ServerError::InvalidConfig(format!("{:?}", e)))
+}
+
+/// Send metrics with another statsd client, gauges already being written
+/// finish with the previous client.
+pub(crate) fn set_statsd_client(client: dogstatsd::Client) {
+    *METRICS.write() = Arc::new(client);
+}
+
+/// Whether the statsd host resolves to an address metrics can be sent to
+pub async fn statsd_resolves() -> bool {
+    let runtime_config = runtime_config::get();
+    let addr = format!(
+        "{}:{}",
+        runtime_config.statsd_host, runtime_config.statsd_port
+    );
+
+    tokio::net::lookup_host(addr)
+        .await
+        .map(|mut addrs| addrs.next().is_some())
+        .unwrap_or(false)
+}
+
+/// Write a gauge to every configured metrics backend
+fn gauge(stat: &str, value: String, tags: &[&String]) {
+    for backend in runtime_config::metrics_backends() {
+        match backend {
+            MetricsBackend::Statsd => {
+                let client = METRICS.read().clone();
+                let _ = client.gauge(stat, value.as_str(), tags);
+            }
+            MetricsBackend::Log => info!(target: "metrics", "{} {} {:?}", stat, value, tags),
+        }
+    }
+}
+
+/// Write a sample of a distribution to every configured metrics backend
+fn distribution(stat: &str, value: String, tags: &[&String]) {
+    for backend in runtime_config::metrics_backends() {
+        match backend {
+            MetricsBackend::Statsd => {
+                let client = METRICS.read().clone();
+                let _ = client.distribution(stat, value.as_str(), tags);
+            }
+            MetricsBackend::Log => info!(target: "metrics", "{} {} {:?}", stat, value, tags),
+        }
+    }
 }
 
 pub fn write_video_rx_stats(stat: &ArcasVideoReceiverStats, pc_id: &str, sess_id: &str) {
@@ -20,52 +80,52 @@ pub fn write_video_rx_stats(stat: &ArcasVideoReceiverStats, pc_id: &str, sess_id
         &format!("ssrc: {}", stat.ssrc),
     ];
 
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.packets_received",
         stat.packets_received.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.packets_lost",
         stat.packets_lost.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.packets_repaired",
         stat.packets_repaired.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.bytes_received",
         stat.bytes_received.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.frames_decoded",
         stat.frames_decoded.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.keyframes_decoded",
         stat.keyframes_decoded.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.frames_dropped",
         stat.frames_dropped.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.total_decode_time",
         stat.total_decode_time.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.frame_width",
         stat.frame_width.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.rx.frame_height",
         stat.frame_height.to_string(),
         tags,
@@ -73,67 +133,115 @@ pub fn write_video_rx_stats(stat: &ArcasVideoReceiverStats, pc_id: &str, sess_id
 }
 
 pub fn write_video_tx_stats(stat: &ArcasVideoSenderStats, pc_id: &str, sess_id: &str) {
-    let tags = [
+    let tags = &[
         &format!("pc_id:{}", pc_id),
         &format!("sess_id:{}", sess_id),
         &format!("ssrc: {}", stat.ssrc),
     ];
 
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.tx.packets_sent",
         stat.packets_sent.to_string(),
         tags,
     );
-    let _ = METRICS.gauge("pc.video.tx.bytes_sent", stat.bytes_sent.to_string(), tags);
-    let _ = METRICS.gauge(
+    gauge("pc.video.tx.bytes_sent", stat.bytes_sent.to_string(), tags);
+    gauge(
         "pc.video.tx.frames_encoded",
         stat.frames_encoded.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.tx.keyframes_encoded",
         stat.key_frames_encoded.to_string(),
         tags,
     );
 
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.tx.total_encode_time",
         stat.total_encode_time.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.tx.frame_width",
         stat.frame_width.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.tx.frame_height",
         stat.frame_height.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.tx.total_packet_send_delay",
         stat.total_packet_send_delay.to_string(),
         tags,
     );
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.tx.remote_jitter",
         stat.remote_jitter.to_string(),
         tags,
     );
 
-    let _ = METRICS.gauge("pc.video.tx.nack_count", stat.nack_count.to_string(), tags);
-    let _ = METRICS.gauge("pc.video.tx.fir_count", stat.fir_count.to_string(), tags);
-    let _ = METRICS.gauge("pc.video.tx.pli_count", stat.pli_count.to_string(), tags);
-    let _ = METRICS.gauge(
+    gauge("pc.video.tx.nack_count", stat.nack_count.to_string(), tags);
+    gauge("pc.video.tx.fir_count", stat.fir_count.to_string(), tags);
+    gauge("pc.video.tx.pli_count", stat.pli_count.to_string(), tags);
+    gauge(
         "pc.video.tx.remote_packets_lost",
         stat.remote_packets_lost.to_string(),
         tags,
     );
 
-    let _ = METRICS.gauge(
+    gauge(
         "pc.video.tx.remote_round_trip_time",
         stat.remote_round_trip_time.to_string(),
         tags,
     );
+
+    let reason = format!(
+        "reason:{}",
+        QualityLimitation::from(stat.quality_limitation_reason).to_string()
+    );
+    let limitation_tags = &[tags[0], tags[1], tags[2], &reason];
+    gauge(
+        "pc.video.tx.quality_limitation_reason",
+        stat.quality_limitation_reason.to_string(),
+        limitation_tags,
+    );
+    gauge(
+        "pc.video.tx.quality_limitation_resolution_changes",
+        stat.quality_limitation_resolution_changes.to_string(),
+        tags,
+    );
+}
+
+/// Write how late a stats tick of a session fired and how long its collection took
+pub fn write_collector_stats(tick: &Tick, sess_id: &str, collection_time: Duration) {
+    let tags = &[&format!("sess_id:{}", sess_id)];
+
+    gauge(
+        "session.stats.lateness_ms",
+        tick.lateness.as_millis().to_string(),
+        tags,
+    );
+    gauge(
+        "session.stats.skipped_ticks",
+        tick.skipped.to_string(),
+        tags,
+    );
+    gauge(
+        "session.stats.collection_ms",
+        collection_time.as_millis().to_string(),
+        tags,
+    );
+}
+
+/// Write the time from the creation of a peer connection to a milestone
+pub fn write_setup_latency(milestone: Milestone, duration: Duration, pc_id: &str, sess_id: &str) {
+    let tags = &[&format!("pc_id:{}", pc_id), &format!("sess_id:{}", sess_id)];
+
+    distribution(
+        &format!("pc.setup.{}_ms", milestone.to_string()),
+        duration.as_millis().to_string(),
+        tags,
+    );
 }
@@ -1,15 +1,18 @@
+use crate::collector::fetch_stats;
 use crate::error::{Result, ServerError};
-use crate::metrics::{write_video_rx_stats, write_video_tx_stats};
+use crate::lifecycle::{Lifecycle, Milestone};
+use crate::media_detection::{DetectionRules, MediaFlow};
+use crate::metrics::{write_setup_latency, write_video_rx_stats, write_video_tx_stats};
+use crate::stats::PeerConnectionSample;
 use crate::webrtc_pool::WebRTCPool;
 
 use core::fmt;
 use libwebrtc::empty_frame_producer::EmptyFrameProducer;
 use libwebrtc::encoded_video_frame_producer::DEFAULT_FPS;
-use libwebrtc::error::WebRTCError;
 use libwebrtc::ice_candidate::ICECandidate;
 use libwebrtc::peer_connection::{
-    PeerConnection, PeerConnectionConfig, PeerConnectionFactory, VideoReceiverStats,
-    VideoSenderStats,
+    PeerConnection, PeerConnectionConfig, PeerConnectionFactory, PeerConnectionStats,
+    VideoReceiverStats, VideoSenderStats,
 };
 use libwebrtc::peer_connection_observer::{ConnectionState, ObserverSenders};
 use libwebrtc::sdp::{SDPType, SessionDescription};
@@ -17,18 +20,23 @@ use libwebrtc::transceiver::{AudioTransceiver, TransceiverInit, VideoTransceiver
 use libwebrtc::video_track::VideoTrack;
 use libwebrtc::video_track_source::VideoTrackSource;
 use libwebrtc_sys::ffi::ArcasVideoSenderStats;
-use tokio::sync::mpsc::{channel, Receiver};
-use tracing::warn;
+use parking_lot::Mutex;
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::timeout;
+use tracing::{debug, instrument, warn};
 
 // Store the last bytes_sent in the enum
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, strum::ToString)]
 pub(crate) enum VideoSendState {
     Sending(u64),
     NotSending(u64),
 }
 
 // Store the last bytes_received in the enum
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, strum::ToString)]
 pub(crate) enum VideoReceiveState {
     Receiving(u64),
     NotReceiving(u64),
@@ -40,11 +48,159 @@ const CONNECTION_STATE_BUFFERING: usize = 100;
 const ICE_CANDIDATE_BUFFERING: usize = 100;
 // We *may* have more video tracks than this at a time but it's highly unlikely.
 const VIDEO_TRACK_BUFFERING: usize = 100;
+// Host candidates on a loopback pair are gathered almost immediately.
+const LOOPBACK_ICE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long the frame pusher waits for a frame before checking for a resumed producer.
+const FRAME_PRODUCER_POLL: Duration = Duration::from_millis(50);
+// How often the stats of a connected peer connection are polled for its first packet and frame.
+const MEDIA_MILESTONE_POLL: Duration = Duration::from_millis(100);
+// How long after connecting the first packet and frame are polled for, the
+// collector marks them at the stats interval after that.
+const MEDIA_MILESTONE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Pushes empty frames into a video source and can be paused and resumed.
+pub(crate) struct FrameProducer {
+    producer: EmptyFrameProducer,
+    restart: Box<dyn Fn() -> Result<EmptyFrameProducer> + Send + Sync>,
+    running: bool,
+}
+
+impl FrameProducer {
+    pub(crate) fn cancel(&mut self) {
+        if self.running {
+            self.producer.cancel();
+            self.running = false;
+        }
+    }
+
+    pub(crate) fn resume(&mut self) -> Result<()> {
+        if !self.running {
+            self.producer = (self.restart)()?;
+            self.running = true;
+        }
+
+        Ok(())
+    }
+}
+
+/// Push the frames of the latest producer handed over on `producers`.
+///
+/// A new producer replaces the current one right away, without waiting for the
+/// frames of the old one to end.  Returns once all producers are gone.
+fn push_frames<T>(
+    producers: std::sync::mpsc::Receiver<std::sync::mpsc::Receiver<T>>,
+    mut push: impl FnMut(T),
+) {
+    let mut frames = match producers.recv() {
+        Ok(frames) => frames,
+        Err(_) => return,
+    };
+
+    loop {
+        match producers.try_recv() {
+            Ok(next) => {
+                drop(std::mem::replace(&mut frames, next));
+                continue;
+            }
+            Err(TryRecvError::Disconnected) => return,
+            Err(TryRecvError::Empty) => {}
+        }
+
+        match frames.recv_timeout(FRAME_PRODUCER_POLL) {
+            Ok(frame) => push(frame),
+            Err(RecvTimeoutError::Timeout) => {}
+            // a finished producer, wait for the next one
+            Err(RecvTimeoutError::Disconnected) => match producers.recv() {
+                Ok(next) => frames = next,
+                Err(_) => return,
+            },
+        }
+    }
+}
+
+/// Keep the last connection state reported by libwebrtc, marking when it connected.
+async fn watch_connection_state(
+    mut states: Receiver<ConnectionState>,
+    connection_state: Arc<Mutex<Option<ConnectionState>>>,
+    lifecycle: Arc<Lifecycle>,
+    peer_connection: Weak<PeerConnection>,
+) {
+    while let Some(state) = states.recv().await {
+        *connection_state.lock() = Some(state);
+
+        if state == ConnectionState::Connected && lifecycle.mark(Milestone::Connected) {
+            tokio::spawn(watch_media_milestones(
+                peer_connection.clone(),
+                lifecycle.clone(),
+            ));
+        }
+    }
+}
+
+/// Mark the first ICE candidate as it's gathered, whether or not anything reads it.
+async fn forward_ice_candidates(
+    mut gathered: Receiver<ICECandidate>,
+    candidates: Sender<ICECandidate>,
+    lifecycle: Arc<Lifecycle>,
+) {
+    while let Some(candidate) = gathered.recv().await {
+        lifecycle.mark(Milestone::FirstIceCandidate);
+        // nobody reads the candidates once the observer stream is gone
+        candidates.send(candidate).await.ok();
+    }
+}
+
+/// Poll the stats of a connected peer connection for its first packet and
+/// frame, independently of the stats interval and of the session being paused.
+async fn watch_media_milestones(peer_connection: Weak<PeerConnection>, lifecycle: Arc<Lifecycle>) {
+    let deadline = Instant::now() + MEDIA_MILESTONE_TIMEOUT;
+    let mut poll = tokio::time::interval(MEDIA_MILESTONE_POLL);
+
+    while Instant::now() < deadline
+        && !(lifecycle.reached(Milestone::FirstPacketSent)
+            && lifecycle.reached(Milestone::FirstFrameDecoded))
+    {
+        poll.tick().await;
+
+        // closed since it connected
+        let peer_connection = match peer_connection.upgrade() {
+            Some(peer_connection) => peer_connection,
+            None => return,
+        };
+
+        for (_, stats) in fetch_stats(vec![(String::new(), peer_connection)]).await {
+            match stats {
+                Ok(stats) => mark_media_milestones(&lifecycle, &stats),
+                Err(e) => debug!("could not poll media milestones: {}", e),
+            }
+        }
+    }
+}
+
+fn mark_media_milestones(lifecycle: &Lifecycle, stats: &PeerConnectionStats) {
+    if stats
+        .video_sender_stats
+        .iter()
+        .any(|stat| stat.packets_sent > 0)
+    {
+        lifecycle.mark(Milestone::FirstPacketSent);
+    }
+
+    if stats
+        .video_receiver_stats
+        .iter()
+        .any(|stat| stat.frames_decoded > 0)
+    {
+        lifecycle.mark(Milestone::FirstFrameDecoded);
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct PeerConnectionState {
     pub(crate) video_send: VideoSendState,
     pub(crate) video_receive: VideoReceiveState,
+    pub(crate) send: MediaFlow,
+    pub(crate) receive: MediaFlow,
 }
 
 // TODO: temp allowing dead code, only used in tests currently
@@ -52,14 +208,44 @@ pub(crate) struct PeerConnectionState {
 pub(crate) struct PeerConnectionManager {
     pub(crate) id: String,
     pub(crate) name: String,
-    pub(crate) webrtc_peer_connection: PeerConnection,
+    // shared with stats collection, which fetches stats without holding a guard
+    pub(crate) webrtc_peer_connection: Arc<PeerConnection>,
     pub(crate) pool_id: u32,
     pub(crate) state: PeerConnectionState,
+    // The last connection state reported by libwebrtc, None until the first change
+    connection_state: Arc<Mutex<Option<ConnectionState>>>,
+    // handed to the watcher once the events are watched
     connection_state_rx: Option<Receiver<ConnectionState>>,
+    pub(crate) lifecycle: Arc<Lifecycle>,
+    pub(crate) sample: PeerConnectionSample,
     ice_candidates_rx: Option<Receiver<ICECandidate>>,
     video_track_rx: Option<Receiver<VideoTransceiver>>,
 }
 
+impl From<&PeerConnectionManager> for crate::server::webrtc::PeerConnectionInfo {
+    fn from(peer_connection: &PeerConnectionManager) -> Self {
+        Self {
+            id: peer_connection.id.clone(),
+            name: peer_connection.name.clone(),
+            pool_id: peer_connection.pool_id,
+            send_state: peer_connection.state.video_send.to_string(),
+            receive_state: peer_connection.state.video_receive.to_string(),
+            connection_state: peer_connection
+                .connection_state()
+                .map(|connection_state| format!("{:?}", connection_state))
+                .unwrap_or_else(|| "New".into()),
+            send: Some((&peer_connection.state.send).into()),
+            receive: Some((&peer_connection.state.receive).into()),
+            setup_ms: peer_connection
+                .lifecycle
+                .durations()
+                .into_iter()
+                .map(|(milestone, duration)| (milestone.to_string(), duration.as_millis() as u64))
+                .collect(),
+        }
+    }
+}
+
 impl fmt::Debug for PeerConnectionManager {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "id={}, name={}", self.id, self.name)
@@ -77,26 +263,33 @@ impl PeerConnectionManager {
         let (ice_candidates_tx, ice_candidates_rx) = channel(ICE_CANDIDATE_BUFFERING);
         let (video_track_tx, video_track_rx) = channel(VIDEO_TRACK_BUFFERING);
 
-        let webrtc_peer_connection = peer_connection_factory.create_peer_connection(
-            PeerConnectionConfig::default(),
-            ObserverSenders {
-                connection_state: Some(connection_state_tx),
-                ice_candidate: Some(ice_candidates_tx),
-                video_track: Some(video_track_tx),
-                ..Default::default()
-            },
-        )?;
+        let webrtc_peer_connection = peer_connection_factory
+            .create_peer_connection(
+                PeerConnectionConfig::default(),
+                ObserverSenders {
+                    connection_state: Some(connection_state_tx),
+                    ice_candidate: Some(ice_candidates_tx),
+                    video_track: Some(video_track_tx),
+                    ..Default::default()
+                },
+            )
+            .map_err(|e| ServerError::CreatePeerConnectionError(e.into()))?;
 
         let pc = PeerConnectionManager {
             id,
             name,
-            webrtc_peer_connection,
+            webrtc_peer_connection: Arc::new(webrtc_peer_connection),
             pool_id,
             state: PeerConnectionState {
                 video_send: VideoSendState::NotSending(0),
                 video_receive: VideoReceiveState::NotReceiving(0),
+                send: MediaFlow::new(Instant::now()),
+                receive: MediaFlow::new(Instant::now()),
             },
+            connection_state: Arc::new(Mutex::new(None)),
             connection_state_rx: Some(connection_state_rx),
+            lifecycle: Arc::new(Lifecycle::default()),
+            sample: PeerConnectionSample::default(),
             ice_candidates_rx: Some(ice_candidates_rx),
             video_track_rx: Some(video_track_rx),
         };
@@ -104,6 +297,31 @@ impl PeerConnectionManager {
         Ok(pc)
     }
 
+    /// Watch the connection state and the gathered ICE candidates, marking the
+    /// setup milestones as the events arrive rather than when they're read.
+    ///
+    /// Must be called from within the runtime, before the candidates are taken.
+    pub(crate) fn watch_events(&mut self) {
+        if let Some(states) = self.connection_state_rx.take() {
+            tokio::spawn(watch_connection_state(
+                states,
+                self.connection_state.clone(),
+                self.lifecycle.clone(),
+                Arc::downgrade(&self.webrtc_peer_connection),
+            ));
+        }
+
+        if let Some(gathered) = self.ice_candidates_rx.take() {
+            let (candidates_tx, candidates_rx) = channel(ICE_CANDIDATE_BUFFERING);
+            tokio::spawn(forward_ice_candidates(
+                gathered,
+                candidates_tx,
+                self.lifecycle.clone(),
+            ));
+            self.ice_candidates_rx = Some(candidates_rx);
+        }
+    }
+
     /// Send the callback to the rust ffi bindings and just listen for the first message.
     ///
     /// If the message fails, just return an empty vec.
@@ -112,34 +330,101 @@ impl PeerConnectionManager {
         Ok(stats.video_sender_stats)
     }
 
+    #[instrument(skip_all, fields(peer_connection_id = %self.id))]
     pub(crate) async fn create_offer(&self) -> Result<SessionDescription> {
-        let offer = self.webrtc_peer_connection.create_offer().await?;
+        let offer = self
+            .webrtc_peer_connection
+            .create_offer()
+            .await
+            .map_err(|e| ServerError::CouldNotCreateOffer(e.into()))?;
+        self.lifecycle.mark(Milestone::OfferCreated);
         Ok(offer)
     }
 
+    #[instrument(skip_all, fields(peer_connection_id = %self.id))]
     pub(crate) async fn create_answer(&self) -> Result<SessionDescription> {
-        let answer = self.webrtc_peer_connection.create_answer().await?;
+        let answer = self
+            .webrtc_peer_connection
+            .create_answer()
+            .await
+            .map_err(|e| ServerError::CouldNotCreateAnswer(e.into()))?;
         Ok(answer)
     }
 
+    #[instrument(skip_all, fields(peer_connection_id = %self.id))]
     pub(crate) async fn set_local_description(&self, sdp_type: SDPType, sdp: String) -> Result<()> {
-        let sdp = SessionDescription::new(sdp_type, sdp)?;
-        Ok(self
-            .webrtc_peer_connection
+        let sdp = SessionDescription::new(sdp_type, sdp)
+            .map_err(|e| ServerError::CouldNotParseSdp(e.into()))?;
+        self.webrtc_peer_connection
             .set_local_description(sdp)
-            .await?)
+            .await
+            .map_err(|e| ServerError::CouldNotSetSdp(e.into()))
     }
 
+    #[instrument(skip_all, fields(peer_connection_id = %self.id))]
     pub(crate) async fn set_remote_description(
         &self,
         sdp_type: SDPType,
         sdp: String,
     ) -> Result<()> {
-        let sdp = SessionDescription::new(sdp_type, sdp)?;
-        Ok(self
-            .webrtc_peer_connection
+        let sdp = SessionDescription::new(sdp_type, sdp)
+            .map_err(|e| ServerError::CouldNotParseSdp(e.into()))?;
+        self.webrtc_peer_connection
             .set_remote_description(sdp)
-            .await?)
+            .await
+            .map_err(|e| ServerError::CouldNotSetSdp(e.into()))?;
+        self.lifecycle.mark(Milestone::RemoteDescriptionSet);
+        Ok(())
+    }
+
+    /// Negotiate this peer connection directly with `receiver`, without an SFU.
+    ///
+    /// The offer, answer and the first ICE candidate of each side are exchanged
+    /// in-process.  This consumes both ICE candidate receivers, so the observer
+    /// stream is not available for either peer connection afterwards.
+    #[instrument(skip_all, fields(peer_connection_id = %self.id))]
+    pub(crate) async fn connect_loopback(
+        &mut self,
+        receiver: &mut PeerConnectionManager,
+    ) -> Result<()> {
+        let offer = self.create_offer().await?;
+        self.set_local_description(offer.get_type(), offer.to_string())
+            .await?;
+        receiver
+            .set_remote_description(offer.get_type(), offer.to_string())
+            .await?;
+
+        let answer = receiver.create_answer().await?;
+        receiver
+            .set_local_description(answer.get_type(), answer.to_string())
+            .await?;
+        self.set_remote_description(answer.get_type(), answer.to_string())
+            .await?;
+
+        let candidate = Self::first_ice_candidate(self.ice_candidates_rx()?).await?;
+        let receiver_candidate = Self::first_ice_candidate(receiver.ice_candidates_rx()?).await?;
+        self.webrtc_peer_connection
+            .add_ice_candidate(receiver_candidate)
+            .await
+            .map_err(|e| ServerError::CouldNotAddIceCandidate(e.into()))?;
+        receiver
+            .webrtc_peer_connection
+            .add_ice_candidate(candidate)
+            .await
+            .map_err(|e| ServerError::CouldNotAddIceCandidate(e.into()))?;
+
+        Ok(())
+    }
+
+    async fn first_ice_candidate(mut rx: Receiver<ICECandidate>) -> Result<ICECandidate> {
+        timeout(LOOPBACK_ICE_TIMEOUT, rx.recv())
+            .await
+            .map_err(|_| {
+                ServerError::CouldNotCreateLoopbackPair("timed out gathering ice candidates".into())
+            })?
+            .ok_or_else(|| {
+                ServerError::CouldNotCreateLoopbackPair("ice candidate channel closed".into())
+            })
     }
 
     /// NOTE: This is *not* async as media calls are generally intended to be run syncrhonously within
@@ -151,15 +436,17 @@ impl PeerConnectionManager {
         label: String,
     ) -> Result<VideoTrack> {
         let peer_connection_factory = pool.factory_list.get(&pool_id).ok_or_else(|| {
-            WebRTCError::UnexpectedError(format!("unknown factory id: {}", &pool_id))
+            ServerError::InternalError(format!("unknown factory id: {}", &pool_id))
         })?;
         let value = peer_connection_factory
             .value()
             .peer_connection_factory
-            .create_video_track(label, video_source)?;
+            .create_video_track(label, video_source)
+            .map_err(|e| ServerError::CouldNotCreateTrack(e.into()))?;
         Ok(value)
     }
 
+    #[instrument(skip_all, fields(peer_connection_id = %self.id))]
     pub(crate) async fn add_track(
         &self,
         pool: &WebRTCPool,
@@ -167,12 +454,13 @@ impl PeerConnectionManager {
         label: String,
     ) -> Result<()> {
         let track = Self::create_track(self.pool_id, pool, video_source, label)?;
-        Ok(self
-            .webrtc_peer_connection
+        self.webrtc_peer_connection
             .add_video_track(vec!["0".into()], track)
-            .await?)
+            .await
+            .map_err(|e| ServerError::CouldNotAddTrack(e.into()))
     }
 
+    #[instrument(skip_all, fields(peer_connection_id = %self.id))]
     pub(crate) async fn add_transceiver(
         &self,
         pool: &WebRTCPool,
@@ -187,12 +475,13 @@ impl PeerConnectionManager {
         let value = self
             .webrtc_peer_connection
             .add_video_transceiver(init, track)
-            .await?;
+            .await
+            .map_err(|e| ServerError::CouldNotAddTransceiver(e.into()))?;
         Ok(value)
     }
 
     // stream a pre-encoded file from gstreamer to avoid encoding overhead
-    pub(crate) fn file_video_source() -> Result<(VideoTrackSource, EmptyFrameProducer)> {
+    pub(crate) fn file_video_source() -> Result<(VideoTrackSource, FrameProducer)> {
         let (source, source_writer) = VideoTrackSource::create();
         // The empty frame producer ensures we receive the right messages from
         // the encoder factory without actually sending any frames. These
@@ -203,51 +492,76 @@ impl PeerConnectionManager {
         let frame = rx.recv().unwrap();
         source_writer.push_empty_frame(frame).unwrap();
 
+        // A cancelled producer can't be restarted, so resuming creates a new
+        // producer and hands its frames over to this thread.
+        let (producers_tx, producers_rx) = std::sync::mpsc::channel();
+        producers_tx.send(rx).unwrap();
+
         std::thread::spawn(move || {
-            while let Ok(frame) = rx.recv() {
-                match source_writer.push_empty_frame(frame) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        warn!("error pushing frame: {}", err);
-                    }
+            push_frames(producers_rx, |frame| {
+                if let Err(err) = source_writer.push_empty_frame(frame) {
+                    warn!("error pushing frame: {}", err);
                 }
-            }
+            })
         });
 
-        Ok((source, producer))
+        let producers_tx = Mutex::new(producers_tx);
+        let restart = move || -> Result<EmptyFrameProducer> {
+            let mut producer = EmptyFrameProducer::new(DEFAULT_FPS)?;
+            let rx = producer.start()?;
+            producers_tx
+                .lock()
+                .send(rx)
+                .map_err(|_| ServerError::InternalError("frame producer thread exited".into()))?;
+            Ok(producer)
+        };
+
+        Ok((
+            source,
+            FrameProducer {
+                producer,
+                restart: Box::new(restart),
+                running: true,
+            },
+        ))
     }
 
-    // Export stats
-    pub(crate) async fn export_stats(
+    // Export stats fetched from the peer connection
+    #[instrument(level = "debug", skip_all, fields(peer_connection_id = %self.id))]
+    pub(crate) fn export_stats(
         &mut self,
-        session_id: &String,
+        session_id: &str,
+        stats: &PeerConnectionStats,
         should_poll_state: bool,
-    ) -> Result<()> {
-        let pc_id = self.id.clone();
-        let stats = self.webrtc_peer_connection.get_stats().await?;
+        rules: &DetectionRules,
+    ) {
+        self.sample = stats.into();
+
+        if should_poll_state {
+            self.set_receive_state(&stats.video_receiver_stats, rules);
+            self.set_send_state(&stats.video_sender_stats, rules);
+        }
+
+        mark_media_milestones(&self.lifecycle, stats);
+
+        for (milestone, duration) in self.lifecycle.take_unexported() {
+            write_setup_latency(milestone, duration, &self.id, session_id);
+        }
 
         for stat in &stats.video_receiver_stats {
-            log::trace!("{:?}", stat);
-            if should_poll_state {
-                self.set_receive_state(stat);
-            }
-            write_video_rx_stats(stat, &pc_id, &session_id);
+            tracing::trace!("{:?}", stat);
+            write_video_rx_stats(stat, &self.id, session_id);
         }
 
         for stat in &stats.video_sender_stats {
-            log::trace!("{:?}", stat);
-            if should_poll_state {
-                self.set_send_state(stat);
-            }
-            write_video_tx_stats(stat, &pc_id, &session_id);
+            tracing::trace!("{:?}", stat);
+            write_video_tx_stats(stat, &self.id, session_id);
         }
-        Ok(())
     }
 
-    pub fn _connection_state_rx(&mut self) -> Result<Receiver<ConnectionState>> {
-        self.connection_state_rx.take().ok_or_else(|| {
-            ServerError::InternalError("connection_state_rx already taken".to_string())
-        })
+    /// The last connection state reported by libwebrtc, None until the first change
+    pub(crate) fn connection_state(&self) -> Option<ConnectionState> {
+        *self.connection_state.lock()
     }
 
     pub fn ice_candidates_rx(&mut self) -> Result<Receiver<ICECandidate>> {
@@ -262,48 +576,90 @@ impl PeerConnectionManager {
             .ok_or_else(|| ServerError::InternalError("video_track_rx already taken".to_string()))
     }
 
+    #[instrument(skip_all, fields(peer_connection_id = %self.id))]
     pub(crate) async fn get_transceivers(&self) -> (Vec<VideoTransceiver>, Vec<AudioTransceiver>) {
         self.webrtc_peer_connection.get_transceivers()
     }
 
     /// Set the send state for a peer connection
-    pub(crate) fn set_send_state(&mut self, video_sender_stats: &VideoSenderStats) {
-        self.state.video_send = if self.is_sending(video_sender_stats.bytes_sent) {
-            VideoSendState::Sending(video_sender_stats.bytes_sent)
+    pub(crate) fn set_send_state(
+        &mut self,
+        video_sender_stats: &[VideoSenderStats],
+        rules: &DetectionRules,
+    ) {
+        let bytes_sent = video_sender_stats.iter().map(|stat| stat.bytes_sent).sum();
+        let frames_encoded = video_sender_stats
+            .iter()
+            .map(|stat| stat.frames_encoded as u64)
+            .sum();
+
+        self.state.video_send = if self.is_sending(bytes_sent, frames_encoded, rules) {
+            VideoSendState::Sending(bytes_sent)
         } else {
-            VideoSendState::NotSending(video_sender_stats.bytes_sent)
+            VideoSendState::NotSending(bytes_sent)
         };
     }
 
-    /// Determine is the peer connection is send.
-    /// Rule: is send if bytes_sent are incrementing
-    fn is_sending(&self, bytes_sent: u64) -> bool {
-        let last_bytes_sent = match self.state.video_send {
-            VideoSendState::Sending(bytes_sent) => bytes_sent,
-            VideoSendState::NotSending(bytes_sent) => bytes_sent,
-        };
+    /// Determine is the peer connection is sending.
+    /// Rule: is sending once the session's detection rules hold for enough polls
+    fn is_sending(&mut self, bytes_sent: u64, frames_encoded: u64, rules: &DetectionRules) -> bool {
+        let was_sending = self.state.send.active;
+        let sending = self
+            .state
+            .send
+            .sample(rules, bytes_sent, frames_encoded, Instant::now());
+
+        if sending != was_sending {
+            debug!("peer connection {} sending changed to {}", self.id, sending);
+        }
 
-        bytes_sent > last_bytes_sent
+        sending
     }
 
     /// Set the receiving state for a peer connection
-    pub(crate) fn set_receive_state(&mut self, video_receiver_stats: &VideoReceiverStats) {
-        self.state.video_receive = if self.is_receiving(video_receiver_stats.bytes_received) {
-            VideoReceiveState::Receiving(video_receiver_stats.bytes_received)
+    pub(crate) fn set_receive_state(
+        &mut self,
+        video_receiver_stats: &[VideoReceiverStats],
+        rules: &DetectionRules,
+    ) {
+        let bytes_received = video_receiver_stats
+            .iter()
+            .map(|stat| stat.bytes_received)
+            .sum();
+        let frames_decoded = video_receiver_stats
+            .iter()
+            .map(|stat| stat.frames_decoded as u64)
+            .sum();
+
+        self.state.video_receive = if self.is_receiving(bytes_received, frames_decoded, rules) {
+            VideoReceiveState::Receiving(bytes_received)
         } else {
-            VideoReceiveState::NotReceiving(video_receiver_stats.bytes_received)
+            VideoReceiveState::NotReceiving(bytes_received)
         };
     }
 
     /// Determine is the peer connection is receiving.
-    /// Rule: is receiving if bytes_received are incrementing
-    fn is_receiving(&self, bytes_received: u64) -> bool {
-        let last_bytes_received = match self.state.video_receive {
-            VideoReceiveState::Receiving(bytes_received) => bytes_received,
-            VideoReceiveState::NotReceiving(bytes_received) => bytes_received,
-        };
+    /// Rule: is receiving once the session's detection rules hold for enough polls
+    fn is_receiving(
+        &mut self,
+        bytes_received: u64,
+        frames_decoded: u64,
+        rules: &DetectionRules,
+    ) -> bool {
+        let was_receiving = self.state.receive.active;
+        let receiving =
+            self.state
+                .receive
+                .sample(rules, bytes_received, frames_decoded, Instant::now());
+
+        if receiving != was_receiving {
+            debug!(
+                "peer connection {} receiving changed to {}",
+                self.id, receiving
+            );
+        }
 
-        bytes_received > last_bytes_received
+        receiving
     }
 }
 
@@ -393,8 +749,10 @@ pub(crate) mod tests {
 
         let _stats = pc._get_stats().await.unwrap();
 
-        pc.export_stats(&session_id, true).await.unwrap();
-        pc_recv.export_stats(&session_id, true).await.unwrap();
+        let stats = pc.webrtc_peer_connection.get_stats().await.unwrap();
+        pc.export_stats(&session_id, &stats, true, &DetectionRules::default());
+        let stats = pc_recv.webrtc_peer_connection.get_stats().await.unwrap();
+        pc_recv.export_stats(&session_id, &stats, true, &DetectionRules::default());
         sleep(Duration::from_millis(200)).await;
     }
 
@@ -452,16 +810,41 @@ pub(crate) mod tests {
     fn it_sets_sending_state() {
         let mut pc = new_peer_connection().0;
         let mut stats = video_receiver_stats();
+        let rules = DetectionRules::default();
         assert_eq!(pc.state.video_send, VideoSendState::NotSending(0));
 
-        pc.set_send_state(&stats);
+        pc.set_send_state(std::slice::from_ref(&stats), &rules);
         assert_eq!(pc.state.video_send, VideoSendState::NotSending(0));
 
         stats.bytes_sent = 100;
-        pc.set_send_state(&stats);
+        pc.set_send_state(std::slice::from_ref(&stats), &rules);
         assert_eq!(pc.state.video_send, VideoSendState::Sending(100));
 
-        pc.set_send_state(&stats);
+        pc.set_send_state(std::slice::from_ref(&stats), &rules);
         assert_eq!(pc.state.video_send, VideoSendState::NotSending(100));
     }
+
+    #[test]
+    fn it_pushes_frames_again_after_a_pause() {
+        let (producers_tx, producers_rx) = std::sync::mpsc::channel();
+        let (pushed_tx, pushed_rx) = std::sync::mpsc::channel();
+        let pusher = std::thread::spawn(move || {
+            push_frames(producers_rx, |frame| pushed_tx.send(frame).unwrap())
+        });
+
+        let (first_tx, first_rx) = std::sync::mpsc::channel();
+        producers_tx.send(first_rx).unwrap();
+        first_tx.send(1).unwrap();
+        assert_eq!(pushed_rx.recv_timeout(Duration::from_secs(1)), Ok(1));
+
+        // a paused producer stops sending frames without closing its channel
+        let (second_tx, second_rx) = std::sync::mpsc::channel();
+        producers_tx.send(second_rx).unwrap();
+        second_tx.send(2).unwrap();
+        assert_eq!(pushed_rx.recv_timeout(Duration::from_secs(1)), Ok(2));
+        assert!(first_tx.send(3).is_err());
+
+        drop(producers_tx);
+        pusher.join().unwrap();
+    }
 }
@@ -0,0 +1,492 @@
+//! The report of a run of a session, from everything the server saw of its
+//! peer connections, as JSON or as a self-contained HTML page.
+
+use crate::error::{Result, ServerError};
+use crate::helpers::percentile;
+use crate::server::webrtc;
+use crate::session::Session;
+use crate::stats::{PeerConnectionSample, QualityLimitation, SessionStats};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Instant, SystemTime};
+
+/// The mean and maximum of a series of values.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Running {
+    count: u64,
+    sum: f64,
+    max: f64,
+}
+
+impl Running {
+    pub(crate) fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.max = self.max.max(value);
+    }
+
+    pub(crate) fn merge(&mut self, other: &Running) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    pub(crate) fn mean(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => self.sum / count as f64,
+        }
+    }
+}
+
+/// What a run saw of a peer connection, kept once it's closed.
+#[derive(Debug, Clone)]
+pub(crate) struct PeerConnectionRecord {
+    pub(crate) name: String,
+    first: Option<(Instant, PeerConnectionSample)>,
+    last: Option<(Instant, PeerConnectionSample)>,
+    round_trip_time_ms: Running,
+    jitter_ms: Running,
+    // stats samples by what limited the outbound streams
+    quality_limitations: BTreeMap<QualityLimitation, u64>,
+    pub(crate) failed: bool,
+}
+
+impl PeerConnectionRecord {
+    pub(crate) fn new(name: String) -> Self {
+        Self {
+            name,
+            first: None,
+            last: None,
+            round_trip_time_ms: Running::default(),
+            jitter_ms: Running::default(),
+            quality_limitations: BTreeMap::new(),
+            failed: false,
+        }
+    }
+
+    pub(crate) fn add(&mut self, sample: &PeerConnectionSample, failed: bool) {
+        let now = Instant::now();
+
+        if self.first.is_none() {
+            self.first = Some((now, sample.clone()));
+        }
+
+        if let Some(round_trip_time_ms) = sample.round_trip_time_ms {
+            self.round_trip_time_ms.add(round_trip_time_ms);
+        }
+
+        if let Some(jitter_ms) = sample.jitter_ms {
+            self.jitter_ms.add(jitter_ms);
+        }
+
+        if let Some(quality_limitation) = sample.quality_limitation {
+            *self
+                .quality_limitations
+                .entry(quality_limitation)
+                .or_default() += 1;
+        }
+
+        self.failed |= failed;
+        self.last = Some((now, sample.clone()));
+    }
+
+    // the growth of a counter per second, between the first and last samples
+    fn rate(&self, counter: impl Fn(&PeerConnectionSample) -> u64) -> f64 {
+        match (&self.first, &self.last) {
+            (Some((first_time, first)), Some((last_time, last))) => {
+                let seconds = last_time.duration_since(*first_time).as_secs_f64();
+
+                if seconds > 0.0 {
+                    counter(last).saturating_sub(counter(first)) as f64 / seconds
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn to_report(&self, id: &str, setup_ms: HashMap<String, u64>) -> webrtc::PeerConnectionReport {
+        let last = self
+            .last
+            .as_ref()
+            .map(|(_, sample)| sample.clone())
+            .unwrap_or_default();
+
+        webrtc::PeerConnectionReport {
+            id: id.into(),
+            name: self.name.clone(),
+            send_bitrate_kbps: self.rate(|sample| sample.bytes_sent) * 8.0 / 1000.0,
+            receive_bitrate_kbps: self.rate(|sample| sample.bytes_received) * 8.0 / 1000.0,
+            packet_loss_percent: loss_percent(&[&last]),
+            mean_rtt_ms: self.round_trip_time_ms.mean(),
+            max_rtt_ms: self.round_trip_time_ms.max,
+            mean_jitter_ms: self.jitter_ms.mean(),
+            max_jitter_ms: self.jitter_ms.max,
+            encode_fps: self.rate(|sample| sample.frames_encoded),
+            decode_fps: self.rate(|sample| sample.frames_decoded),
+            quality_limitations: Some(quality_limitations(&self.quality_limitations)),
+            setup_ms,
+            failed: self.failed,
+        }
+    }
+}
+
+// packets lost out of those sent and received
+fn loss_percent(samples: &[&PeerConnectionSample]) -> f64 {
+    let packets: u64 = samples
+        .iter()
+        .map(|sample| sample.packets_received + sample.packets_lost + sample.packets_sent)
+        .sum();
+    let packets_lost: u64 = samples
+        .iter()
+        .map(|sample| sample.packets_lost + sample.remote_packets_lost)
+        .sum();
+
+    match packets {
+        0 => 0.0,
+        packets => packets_lost as f64 * 100.0 / packets as f64,
+    }
+}
+
+fn quality_limitations(samples: &BTreeMap<QualityLimitation, u64>) -> webrtc::QualityLimitations {
+    let total: u64 = samples.values().sum();
+    let percent = |limitation| match total {
+        0 => 0.0,
+        total => samples.get(&limitation).copied().unwrap_or(0) as f64 * 100.0 / total as f64,
+    };
+
+    webrtc::QualityLimitations {
+        none_percent: percent(QualityLimitation::None),
+        cpu_percent: percent(QualityLimitation::Cpu),
+        bandwidth_percent: percent(QualityLimitation::Bandwidth),
+        other_percent: percent(QualityLimitation::Other),
+    }
+}
+
+// the mean of the values above 0, 0 without any
+fn mean_of_positive(values: impl Iterator<Item = f64>) -> f64 {
+    let mut running = Running::default();
+    values
+        .filter(|value| *value > 0.0)
+        .for_each(|value| running.add(value));
+    running.mean()
+}
+
+/// The report of the current run of a session, the one that ended if it's stopped.
+pub(crate) fn report(session: &Session) -> webrtc::Report {
+    let mut records: Vec<(String, PeerConnectionRecord)> = session
+        .records
+        .iter()
+        .map(|record| (record.key().clone(), record.value().clone()))
+        .collect();
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let peer_connections: Vec<webrtc::PeerConnectionReport> = records
+        .iter()
+        .map(|(id, record)| {
+            let setup_ms = session
+                .lifecycles
+                .get(id)
+                .map(|lifecycle| lifecycle.durations())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(milestone, duration)| (milestone.to_string(), duration.as_millis() as u64))
+                .collect();
+
+            record.to_report(id, setup_ms)
+        })
+        .collect();
+
+    let mut round_trip_time_ms = Running::default();
+    let mut jitter_ms = Running::default();
+    let mut limitations = BTreeMap::new();
+    let mut mean_round_trip_times = vec![];
+
+    for (_, record) in &records {
+        round_trip_time_ms.merge(&record.round_trip_time_ms);
+        jitter_ms.merge(&record.jitter_ms);
+
+        if record.round_trip_time_ms.count > 0 {
+            mean_round_trip_times.push(record.round_trip_time_ms.mean());
+        }
+
+        for (limitation, samples) in &record.quality_limitations {
+            *limitations.entry(*limitation).or_default() += samples;
+        }
+    }
+
+    mean_round_trip_times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let last_samples: Vec<&PeerConnectionSample> = records
+        .iter()
+        .filter_map(|(_, record)| record.last.as_ref().map(|(_, sample)| sample))
+        .collect();
+
+    let totals = webrtc::ReportTotals {
+        peer_connections: records.len() as u32,
+        failed_peer_connections: records.iter().filter(|(_, record)| record.failed).count() as u32,
+        send_bitrate_kbps: peer_connections.iter().map(|pc| pc.send_bitrate_kbps).sum(),
+        receive_bitrate_kbps: peer_connections
+            .iter()
+            .map(|pc| pc.receive_bitrate_kbps)
+            .sum(),
+        packet_loss_percent: loss_percent(&last_samples),
+        mean_rtt_ms: round_trip_time_ms.mean(),
+        p95_rtt_ms: percentile(&mean_round_trip_times, 95.0).unwrap_or(0.0),
+        max_rtt_ms: round_trip_time_ms.max,
+        mean_jitter_ms: jitter_ms.mean(),
+        encode_fps: mean_of_positive(peer_connections.iter().map(|pc| pc.encode_fps)),
+        decode_fps: mean_of_positive(peer_connections.iter().map(|pc| pc.decode_fps)),
+        quality_limitations: Some(quality_limitations(&limitations)),
+    };
+
+    webrtc::Report {
+        generated_at: Some(SystemTime::now().into()),
+        session: Some(SessionStats::from(session).into()),
+        verdict: session.verdict().as_ref().map(Into::into),
+        totals: Some(totals),
+        peer_connections,
+    }
+}
+
+pub(crate) fn to_json(report: &webrtc::Report) -> Result<String> {
+    serde_json::to_string_pretty(report).map_err(|e| ServerError::InternalError(e.to_string()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// a table with a header row, the cells are escaped
+fn table(html: &mut String, headers: &[&str], rows: Vec<Vec<String>>) {
+    html.push_str("<table><tr>");
+    for header in headers {
+        let _ = write!(html, "<th>{}</th>", escape(header));
+    }
+    html.push_str("</tr>");
+
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            let _ = write!(html, "<td>{}</td>", escape(&cell));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>\n");
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:2em}\
+th,td{border:1px solid #ccc;padding:4px 8px;text-align:right}\
+th{background:#f4f4f4}td:first-child,th:first-child{text-align:left}\
+.passed{color:#080}.failed{color:#c00}";
+
+/// A single HTML page with no external resources.
+pub(crate) fn to_html(report: &webrtc::Report) -> String {
+    let session = report.session.clone().unwrap_or_default();
+    let totals = report.totals.clone().unwrap_or_default();
+    let limitations = totals.quality_limitations.clone().unwrap_or_default();
+    let mut html = String::new();
+
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Report of {name}</title><style>{style}</style></head><body>\n<h1>Report of {name}</h1>\n",
+        name = escape(&session.name),
+        style = STYLE
+    );
+
+    table(
+        &mut html,
+        &["Session", "State", "Run", "Elapsed (s)", "Peer connections"],
+        vec![vec![
+            session.id.clone(),
+            session.state.clone(),
+            session.run.to_string(),
+            session.elapsed_time.to_string(),
+            totals.peer_connections.to_string(),
+        ]],
+    );
+
+    if let Some(verdict) = &report.verdict {
+        let (class, outcome) = match verdict.passed {
+            true => ("passed", "Passed"),
+            false => ("failed", "Failed"),
+        };
+        let _ = writeln!(html, "<h2 class=\"{}\">{}</h2>", class, outcome);
+        table(
+            &mut html,
+            &["Threshold", "Limit", "Worst value"],
+            verdict
+                .violations
+                .iter()
+                .map(|violation| {
+                    vec![
+                        violation.threshold.clone(),
+                        format!("{:.2}", violation.limit),
+                        format!("{:.2}", violation.value),
+                    ]
+                })
+                .collect(),
+        );
+    }
+
+    html.push_str("<h2>Totals</h2>\n");
+    table(
+        &mut html,
+        &[
+            "Failed",
+            "Send (kbps)",
+            "Receive (kbps)",
+            "Loss (%)",
+            "Mean RTT (ms)",
+            "p95 RTT (ms)",
+            "Max RTT (ms)",
+            "Mean jitter (ms)",
+            "Encode fps",
+            "Decode fps",
+            "CPU limited (%)",
+            "Bandwidth limited (%)",
+        ],
+        vec![vec![
+            totals.failed_peer_connections.to_string(),
+            format!("{:.1}", totals.send_bitrate_kbps),
+            format!("{:.1}", totals.receive_bitrate_kbps),
+            format!("{:.2}", totals.packet_loss_percent),
+            format!("{:.1}", totals.mean_rtt_ms),
+            format!("{:.1}", totals.p95_rtt_ms),
+            format!("{:.1}", totals.max_rtt_ms),
+            format!("{:.1}", totals.mean_jitter_ms),
+            format!("{:.1}", totals.encode_fps),
+            format!("{:.1}", totals.decode_fps),
+            format!("{:.1}", limitations.cpu_percent),
+            format!("{:.1}", limitations.bandwidth_percent),
+        ]],
+    );
+
+    html.push_str("<h2>Setup latencies</h2>\n");
+    table(
+        &mut html,
+        &[
+            "Milestone",
+            "Count",
+            "Min (ms)",
+            "p50 (ms)",
+            "p95 (ms)",
+            "Max (ms)",
+        ],
+        session
+            .setup_latencies
+            .iter()
+            .map(|latency| {
+                vec![
+                    latency.milestone.clone(),
+                    latency.count.to_string(),
+                    latency.min_ms.to_string(),
+                    latency.p50_ms.to_string(),
+                    latency.p95_ms.to_string(),
+                    latency.max_ms.to_string(),
+                ]
+            })
+            .collect(),
+    );
+
+    html.push_str("<h2>Peer connections</h2>\n");
+    table(
+        &mut html,
+        &[
+            "Peer connection",
+            "Name",
+            "Send (kbps)",
+            "Receive (kbps)",
+            "Loss (%)",
+            "Mean RTT (ms)",
+            "Mean jitter (ms)",
+            "Encode fps",
+            "Decode fps",
+            "CPU limited (%)",
+            "Bandwidth limited (%)",
+            "Connected (ms)",
+            "Failed",
+        ],
+        report
+            .peer_connections
+            .iter()
+            .map(|pc| {
+                let limitations = pc.quality_limitations.clone().unwrap_or_default();
+
+                vec![
+                    pc.id.clone(),
+                    pc.name.clone(),
+                    format!("{:.1}", pc.send_bitrate_kbps),
+                    format!("{:.1}", pc.receive_bitrate_kbps),
+                    format!("{:.2}", pc.packet_loss_percent),
+                    format!("{:.1}", pc.mean_rtt_ms),
+                    format!("{:.1}", pc.mean_jitter_ms),
+                    format!("{:.1}", pc.encode_fps),
+                    format!("{:.1}", pc.decode_fps),
+                    format!("{:.1}", limitations.cpu_percent),
+                    format!("{:.1}", limitations.bandwidth_percent),
+                    pc.setup_ms
+                        .get("connected")
+                        .map(|ms| ms.to_string())
+                        .unwrap_or_default(),
+                    pc.failed.to_string(),
+                ]
+            })
+            .collect(),
+    );
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_records_a_peer_connection() {
+        let mut record = PeerConnectionRecord::new("pc".into());
+        let mut sample = PeerConnectionSample {
+            round_trip_time_ms: Some(100.0),
+            quality_limitation: Some(QualityLimitation::Cpu),
+            ..Default::default()
+        };
+        record.add(&sample, false);
+
+        sample.round_trip_time_ms = Some(300.0);
+        sample.quality_limitation = Some(QualityLimitation::None);
+        sample.packets_sent = 99;
+        sample.remote_packets_lost = 1;
+        record.add(&sample, true);
+
+        let report = record.to_report("id", HashMap::new());
+        let limitations = report.quality_limitations.unwrap();
+        assert_eq!(200.0, report.mean_rtt_ms);
+        assert_eq!(300.0, report.max_rtt_ms);
+        assert_eq!(50.0, limitations.cpu_percent);
+        assert!((report.packet_loss_percent - 1.0101).abs() < 0.001);
+        assert!(report.failed);
+    }
+
+    #[test]
+    fn it_escapes_the_html_report() {
+        let report = webrtc::Report {
+            session: Some(webrtc::SessionStats {
+                name: "<script>".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let html = to_html(&report);
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+    }
+}
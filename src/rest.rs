@@ -0,0 +1,473 @@
+//! HTTP/JSON gateway mirroring the `WebRtc` gRPC service.
+//!
+//! Every RPC is available as `POST /v1/<Method>` with the JSON form of its
+//! request message as the body, e.g. `POST /v1/CreateSession`.  Requests are
+//! dispatched to the same `SharedState` handlers as the gRPC service, so the
+//! behavior is identical.  The `Observer` and `StreamLogs` streams are served
+//! as server-sent events.  The gateway is served over the same TLS config as
+//! the gRPC service.
+
+use crate::auth::{self, Token};
+use crate::config::CONFIG;
+use crate::data::SharedState;
+use crate::error::{Result, ServerError};
+use crate::server::webrtc::web_rtc_server::WebRtc;
+use crate::server::{read_pem, rpc_span};
+use bytes::Bytes;
+use futures::{Future, Stream, StreamExt};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
+use tracing::{debug, info, warn, Instrument};
+
+const PATH_PREFIX: &str = "/v1/";
+// Connections that don't complete the TLS handshake in time are dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_CONCURRENT_TLS_HANDSHAKES: usize = 64;
+// Request messages are small, an SDP is the largest field they carry.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+pub(crate) async fn serve(
+    addr: &str,
+    shared_state: SharedState,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let addr: SocketAddr = addr.parse()?;
+    let handle = move |request: Request<Body>| {
+        let span = rpc_span(request.uri().path());
+        route(shared_state.clone(), request).instrument(span)
+    };
+
+    info!("Starting REST service on {:?}", addr);
+
+    match tls_config()? {
+        Some(tls_config) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| ServerError::InternalError(e.to_string()))?;
+            let connections = tls_connections(listener, TlsAcceptor::from(Arc::new(tls_config)));
+
+            Server::builder(accept::from_stream(connections))
+                .serve(make_service_fn(move |_conn: &TlsStream<TcpStream>| {
+                    let handle = handle.clone();
+                    async move { Ok::<_, Infallible>(service_fn(handle)) }
+                }))
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        None => {
+            Server::bind(&addr)
+                .serve(make_service_fn(move |_conn: &AddrStream| {
+                    let handle = handle.clone();
+                    async move { Ok::<_, Infallible>(service_fn(handle)) }
+                }))
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+    }
+    .map_err(|e| ServerError::InternalError(e.to_string()))?;
+
+    Ok(())
+}
+
+/// The TLS config of the gateway, mirroring the config of the gRPC service.
+fn tls_config() -> Result<Option<ServerConfig>> {
+    // both or neither are set in a validated config
+    let (cert_path, key_path) = match (&CONFIG.tls_cert_path, &CONFIG.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Ok(None),
+    };
+    let invalid = |path: &str| ServerError::InvalidConfig(format!("{}: invalid PEM", path));
+
+    let client_cert_verifier = match &CONFIG.tls_client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut &read_pem(client_ca_path)?[..]) {
+                Ok((valid, _)) if valid > 0 => AllowAnyAuthenticatedClient::new(roots),
+                _ => return Err(invalid(client_ca_path)),
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+    let certs = pemfile::certs(&mut &read_pem(cert_path)?[..]).map_err(|_| invalid(cert_path))?;
+    let key = private_key(&read_pem(key_path)?).ok_or_else(|| invalid(key_path))?;
+
+    let mut tls_config = ServerConfig::new(client_cert_verifier);
+    tls_config
+        .set_single_cert(certs, key)
+        .map_err(|e| ServerError::InvalidConfig(e.to_string()))?;
+    tls_config.set_protocols(&[b"http/1.1".to_vec()]);
+
+    Ok(Some(tls_config))
+}
+
+/// The first PKCS#8 or RSA private key in a PEM file.
+fn private_key(pem: &[u8]) -> Option<PrivateKey> {
+    let pkcs8_keys = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+    let rsa_keys = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+
+    pkcs8_keys.into_iter().chain(rsa_keys).next()
+}
+
+/// Accept connections and complete their TLS handshakes concurrently, dropping
+/// the ones that fail.
+fn tls_connections(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = std::io::Result<TlsStream<TcpStream>>> {
+    let connections = async_stream::stream! {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => yield stream,
+                Err(e) => {
+                    // e.g. too many open files, back off like hyper does
+                    warn!("Failed to accept a REST connection: {}", e);
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    };
+
+    connections
+        .map(move |stream| timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)))
+        .buffer_unordered(MAX_CONCURRENT_TLS_HANDSHAKES)
+        .filter_map(|handshake| async move {
+            match handshake {
+                Ok(Ok(stream)) => Some(Ok(stream)),
+                Ok(Err(e)) => {
+                    debug!("REST TLS handshake failed: {}", e);
+                    None
+                }
+                Err(_) => {
+                    debug!("REST TLS handshake timed out");
+                    None
+                }
+            }
+        })
+}
+
+async fn route(
+    shared_state: SharedState,
+    request: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if request.method() != Method::POST {
+        return Ok(error_response(StatusCode::METHOD_NOT_ALLOWED, None));
+    }
+
+    let method = match request.uri().path().strip_prefix(PATH_PREFIX) {
+        Some(method) => method.to_owned(),
+        None => return Ok(error_response(StatusCode::NOT_FOUND, None)),
+    };
+    // the same bearer token auth as the gRPC service
+    let metadata = MetadataMap::from_headers(request.headers().clone());
+    let token = match auth::authenticate(&metadata) {
+        Ok(token) => token,
+        Err(e) => return Ok(status_response(e.into())),
+    };
+    let body = match read_body(request.into_body()).await {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let state = &shared_state;
+
+    let response = match method.as_str() {
+        "CreateSession" => unary(&body, &token, |r| state.create_session(r)).await,
+        "StartSession" => unary(&body, &token, |r| state.start_session(r)).await,
+        "PauseSession" => unary(&body, &token, |r| state.pause_session(r)).await,
+        "ResumeSession" => unary(&body, &token, |r| state.resume_session(r)).await,
+        "StopSession" => unary(&body, &token, |r| state.stop_session(r)).await,
+        "GetStats" => unary(&body, &token, |r| state.get_stats(r)).await,
+        "GetReport" => unary(&body, &token, |r| state.get_report(r)).await,
+        "ListSessions" => unary(&body, &token, |r| state.list_sessions(r)).await,
+        "ListPeerConnections" => unary(&body, &token, |r| state.list_peer_connections(r)).await,
+        "CreatePeerConnection" => unary(&body, &token, |r| state.create_peer_connection(r)).await,
+        "CreateLoopbackPair" => unary(&body, &token, |r| state.create_loopback_pair(r)).await,
+        "CreateOffer" => unary(&body, &token, |r| state.create_offer(r)).await,
+        "CreateAnswer" => unary(&body, &token, |r| state.create_answer(r)).await,
+        "SetLocalDescription" => unary(&body, &token, |r| state.set_local_description(r)).await,
+        "SetRemoteDescription" => unary(&body, &token, |r| state.set_remote_description(r)).await,
+        "AddTrack" => unary(&body, &token, |r| state.add_track(r)).await,
+        "AddTransceiver" => unary(&body, &token, |r| state.add_transceiver(r)).await,
+        "GetTransceivers" => unary(&body, &token, |r| state.get_transceivers(r)).await,
+        "UpdateServerConfig" => unary(&body, &token, |r| state.update_server_config(r)).await,
+        "Observer" => server_stream(&body, &token, |r| state.observer(r)).await,
+        "StreamLogs" => server_stream(&body, &token, |r| state.stream_logs(r)).await,
+        _ => error_response(StatusCode::NOT_FOUND, None),
+    };
+
+    Ok(response)
+}
+
+/// Buffer a request body, refusing it once it grows over `MAX_BODY_BYTES`.
+async fn read_body(mut body: Body) -> std::result::Result<Bytes, Response<Body>> {
+    let too_large = || error_response(StatusCode::PAYLOAD_TOO_LARGE, None);
+
+    // a Content-Length over the limit is refused before reading anything
+    if HttpBody::size_hint(&body).lower() > MAX_BODY_BYTES as u64 {
+        return Err(too_large());
+    }
+
+    let mut bytes = vec![];
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| status_response(Status::invalid_argument(e.to_string())))?;
+
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes.into())
+}
+
+/// Decode a JSON request, call the gRPC handler and encode its JSON response.
+async fn unary<Req, Res, Fut>(
+    body: &[u8],
+    token: &Option<Token>,
+    handler: impl FnOnce(tonic::Request<Req>) -> Fut,
+) -> Response<Body>
+where
+    Req: DeserializeOwned,
+    Res: Serialize,
+    Fut: Future<Output = std::result::Result<tonic::Response<Res>, Status>>,
+{
+    let request = match decode(body) {
+        Ok(request) => request,
+        Err(e) => return status_response(Status::invalid_argument(e.to_string())),
+    };
+
+    match handler(grpc_request(request, token)).await {
+        Ok(response) => json_response(StatusCode::OK, &response.into_inner()),
+        Err(status) => status_response(status),
+    }
+}
+
+/// Call a server streaming gRPC handler and send its messages as server-sent events.
+async fn server_stream<Req, Res, S, Fut>(
+    body: &[u8],
+    token: &Option<Token>,
+    handler: impl FnOnce(tonic::Request<Req>) -> Fut,
+) -> Response<Body>
+where
+    Req: DeserializeOwned,
+    Res: Serialize,
+    S: Stream<Item = std::result::Result<Res, Status>> + Send + 'static,
+    Fut: Future<Output = std::result::Result<tonic::Response<S>, Status>>,
+{
+    let request = match decode(body) {
+        Ok(request) => request,
+        Err(e) => return status_response(Status::invalid_argument(e.to_string())),
+    };
+    let stream = match handler(grpc_request(request, token)).await {
+        Ok(response) => response.into_inner(),
+        Err(status) => return status_response(status),
+    };
+    let events = stream.map(|message| {
+        let event = match message {
+            Ok(message) => format!("data: {}\n\n", to_json(&message)),
+            Err(status) => format!("event: error\ndata: {}\n\n", status_json(&status)),
+        };
+        Ok::<_, Infallible>(Bytes::from(event))
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .body(Body::wrap_stream(events))
+        .unwrap_or_default()
+}
+
+fn grpc_request<T>(message: T, token: &Option<Token>) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+
+    if let Some(token) = token {
+        request.extensions_mut().insert(token.clone());
+    }
+
+    request
+}
+
+fn decode<T: DeserializeOwned>(body: &[u8]) -> serde_json::Result<T> {
+    // an empty body is the same as an empty message
+    let body = if body.is_empty() { &b"{}"[..] } else { body };
+
+    serde_json::from_slice(body)
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn json_response<T: Serialize>(status_code: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status_code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(to_json(value)))
+        .unwrap_or_default()
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: i32,
+    message: &'a str,
+}
+
+fn status_json(status: &Status) -> String {
+    to_json(&ErrorBody {
+        code: status.code() as i32,
+        message: status.message(),
+    })
+}
+
+fn error_response(status_code: StatusCode, status: Option<&Status>) -> Response<Body> {
+    let body = status.map(status_json).unwrap_or_else(|| {
+        to_json(&ErrorBody {
+            code: Code::Unimplemented as i32,
+            message: status_code.canonical_reason().unwrap_or_default(),
+        })
+    });
+
+    Response::builder()
+        .status(status_code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap_or_default()
+}
+
+fn status_response(status: Status) -> Response<Body> {
+    error_response(http_status(status.code()), Some(&status))
+}
+
+/// Map a gRPC status code to its HTTP equivalent.
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Cancelled => StatusCode::REQUEST_TIMEOUT,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Serde helpers for the `google.protobuf.Timestamp` fields of messages.
+pub(crate) mod timestamp {
+    use prost_types::Timestamp;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Json {
+        seconds: i64,
+        nanos: i32,
+    }
+
+    pub(crate) fn serialize<S: Serializer>(
+        timestamp: &Option<Timestamp>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        timestamp
+            .as_ref()
+            .map(|timestamp| Json {
+                seconds: timestamp.seconds,
+                nanos: timestamp.nanos,
+            })
+            .serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Timestamp>, D::Error> {
+        let timestamp = Option::<Json>::deserialize(deserializer)?;
+        Ok(timestamp.map(|timestamp| Timestamp {
+            seconds: timestamp.seconds,
+            nanos: timestamp.nanos,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::webrtc;
+
+    #[test]
+    fn it_decodes_camel_case_requests() {
+        let request: webrtc::CreatePeerConnectionRequest =
+            decode(br#"{"sessionId": "abc", "name": "pc"}"#).unwrap();
+
+        assert_eq!("abc", request.session_id);
+        assert_eq!("pc", request.name);
+        assert!(request.peer_connection_id.is_empty());
+    }
+
+    #[test]
+    fn it_decodes_an_empty_body() {
+        let request: webrtc::ListSessionsRequest = decode(b"").unwrap();
+
+        assert_eq!(0, request.page_size);
+    }
+
+    #[tokio::test]
+    async fn it_limits_the_body_size() {
+        let body = read_body(Body::from(r#"{"name": "session"}"#))
+            .await
+            .unwrap();
+        assert_eq!(&br#"{"name": "session"}"#[..], &body[..]);
+
+        let sized = Body::from(vec![0; MAX_BODY_BYTES + 1]);
+        let response = read_body(sized).await.unwrap_err();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+        // without a length, the chunks are counted as they arrive
+        let chunks = futures::stream::iter(vec![
+            Ok::<_, Infallible>(vec![0; MAX_BODY_BYTES]),
+            Ok(vec![0]),
+        ]);
+        let response = read_body(Body::wrap_stream(chunks)).await.unwrap_err();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+    }
+
+    #[test]
+    fn it_requires_a_private_key() {
+        assert!(private_key(b"").is_none());
+        let certificate = b"-----BEGIN CERTIFICATE-----\n-----END CERTIFICATE-----\n";
+        assert!(private_key(certificate).is_none());
+    }
+
+    #[test]
+    fn it_maps_grpc_codes_to_http_statuses() {
+        assert_eq!(StatusCode::NOT_FOUND, http_status(Code::NotFound));
+        assert_eq!(StatusCode::CONFLICT, http_status(Code::AlreadyExists));
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            http_status(Code::FailedPrecondition)
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Settings that `UpdateServerConfig` can change while sessions are running,
+//! starting from the loaded config.
+
+use crate::config::{MetricsBackend, CONFIG, MIN_STATS_INTERVAL_MS};
+use crate::error::{Result, ServerError};
+use crate::log::{parse_log_filter, set_log_filter, LogLevel};
+use crate::metrics;
+use crate::server::webrtc;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use std::convert::TryFrom;
+use std::time::Duration;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RuntimeConfig {
+    pub(crate) statsd_host: String,
+    pub(crate) statsd_port: u16,
+    pub(crate) metrics_backends: Vec<MetricsBackend>,
+    pub(crate) stats_interval: Duration,
+    /// The libwebrtc log level of the whole server
+    pub(crate) log_level: LogLevel,
+    /// The filter of the server logs, in the `RUST_LOG` syntax
+    pub(crate) log_filter: String,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            statsd_host: CONFIG.statsd_host.clone(),
+            statsd_port: CONFIG.statsd_port,
+            metrics_backends: CONFIG.metrics_backends.clone(),
+            stats_interval: Duration::from_millis(CONFIG.stats_interval_ms),
+            log_level: LogLevel::default(),
+            log_filter: std::env::var("RUST_LOG").unwrap_or_default(),
+        }
+    }
+}
+
+impl From<RuntimeConfig> for webrtc::ServerConfig {
+    fn from(runtime_config: RuntimeConfig) -> Self {
+        let metrics_backends = runtime_config
+            .metrics_backends
+            .into_iter()
+            .map(|backend| webrtc::MetricsBackend::from(backend) as i32)
+            .collect();
+
+        webrtc::ServerConfig {
+            statsd_host: runtime_config.statsd_host,
+            statsd_port: runtime_config.statsd_port.into(),
+            metrics_backends,
+            stats_interval_ms: runtime_config.stats_interval.as_millis() as u64,
+            log_level: webrtc::LogLevel::from(runtime_config.log_level) as i32,
+            log_filter: runtime_config.log_filter,
+        }
+    }
+}
+
+impl From<MetricsBackend> for webrtc::MetricsBackend {
+    fn from(backend: MetricsBackend) -> Self {
+        match backend {
+            MetricsBackend::Statsd => webrtc::MetricsBackend::Statsd,
+            MetricsBackend::Log => webrtc::MetricsBackend::Log,
+        }
+    }
+}
+
+impl From<webrtc::MetricsBackend> for MetricsBackend {
+    fn from(backend: webrtc::MetricsBackend) -> Self {
+        match backend {
+            webrtc::MetricsBackend::Statsd => MetricsBackend::Statsd,
+            webrtc::MetricsBackend::Log => MetricsBackend::Log,
+        }
+    }
+}
+
+lazy_static! {
+    static ref RUNTIME_CONFIG: RwLock<RuntimeConfig> = RwLock::new(RuntimeConfig::default());
+}
+
+pub(crate) fn get() -> RuntimeConfig {
+    RUNTIME_CONFIG.read().clone()
+}
+
+pub(crate) fn stats_interval() -> Duration {
+    RUNTIME_CONFIG.read().stats_interval
+}
+
+pub(crate) fn metrics_backends() -> Vec<MetricsBackend> {
+    RUNTIME_CONFIG.read().metrics_backends.clone()
+}
+
+/// Apply the set fields of the request and return the new runtime config.
+///
+/// Running sessions and peer connections are left untouched: metrics move
+/// to the new statsd target and the stats interval changes on the next tick.
+pub(crate) fn update(request: webrtc::UpdateServerConfigRequest) -> Result<RuntimeConfig> {
+    // updates are applied one at a time
+    let mut current = RUNTIME_CONFIG.write();
+    let mut runtime_config = current.clone();
+    let webrtc::UpdateServerConfigRequest {
+        statsd_host,
+        statsd_port,
+        metrics_backends,
+        stats_interval_ms,
+        log_level,
+        log_filter,
+    } = request;
+
+    if !statsd_host.is_empty() {
+        runtime_config.statsd_host = statsd_host;
+    }
+
+    if statsd_port != 0 {
+        runtime_config.statsd_port = u16::try_from(statsd_port).map_err(|_| {
+            ServerError::InvalidConfigUpdate(format!("invalid statsd port {}", statsd_port))
+        })?;
+    }
+
+    if !metrics_backends.is_empty() {
+        runtime_config.metrics_backends = metrics_backends
+            .into_iter()
+            .map(|backend| {
+                webrtc::MetricsBackend::from_i32(backend)
+                    .map(MetricsBackend::from)
+                    .ok_or_else(|| {
+                        ServerError::InvalidConfigUpdate(format!(
+                            "invalid metrics backend {}",
+                            backend
+                        ))
+                    })
+            })
+            .collect::<Result<_>>()?;
+    }
+
+    if stats_interval_ms != 0 {
+        if stats_interval_ms < MIN_STATS_INTERVAL_MS {
+            return Err(ServerError::InvalidConfigUpdate(format!(
+                "stats_interval_ms must be at least {}",
+                MIN_STATS_INTERVAL_MS
+            )));
+        }
+
+        runtime_config.stats_interval = Duration::from_millis(stats_interval_ms);
+    }
+
+    if let Some(log_level) = log_level {
+        runtime_config.log_level = log_level.value.into();
+    }
+
+    if !log_filter.is_empty() {
+        runtime_config.log_filter = log_filter;
+    }
+
+    changes(&current, &runtime_config)?.apply()?;
+    *current = runtime_config.clone();
+
+    Ok(runtime_config)
+}
+
+/// What an update changes, prepared before any of it is applied so an
+/// invalid field leaves everything unchanged.
+struct Changes {
+    // the address of the statsd target and its client
+    statsd: Option<(String, dogstatsd::Client)>,
+    log_filter: Option<EnvFilter>,
+    log_level: Option<LogLevel>,
+}
+
+fn changes(current: &RuntimeConfig, runtime_config: &RuntimeConfig) -> Result<Changes> {
+    let statsd = if runtime_config.statsd_host != current.statsd_host
+        || runtime_config.statsd_port != current.statsd_port
+    {
+        let addr = format!(
+            "{}:{}",
+            runtime_config.statsd_host, runtime_config.statsd_port
+        );
+        let client =
+            metrics::statsd_client(&runtime_config.statsd_host, runtime_config.statsd_port)?;
+        Some((addr, client))
+    } else {
+        None
+    };
+    let log_filter = if runtime_config.log_filter != current.log_filter {
+        Some(parse_log_filter(&runtime_config.log_filter)?)
+    } else {
+        None
+    };
+    let log_level = Some(runtime_config.log_level).filter(|level| *level != current.log_level);
+
+    Ok(Changes {
+        statsd,
+        log_filter,
+        log_level,
+    })
+}
+
+impl Changes {
+    fn apply(self) -> Result<()> {
+        if let Some(log_filter) = self.log_filter {
+            set_log_filter(log_filter)?;
+        }
+
+        if let Some((addr, client)) = self.statsd {
+            info!("Attempting to send metrics to {}", addr);
+            metrics::set_statsd_client(client);
+        }
+
+        if let Some(log_level) = self.log_level {
+            LogLevel::set_log_level(&log_level);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_rejects_an_invalid_update() {
+        let before = get();
+        let request = webrtc::UpdateServerConfigRequest {
+            stats_interval_ms: 1,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            update(request),
+            Err(ServerError::InvalidConfigUpdate(_))
+        ));
+        assert_eq!(get(), before);
+    }
+
+    #[test]
+    fn it_applies_nothing_when_a_field_is_invalid() {
+        let before = get();
+        let request = webrtc::UpdateServerConfigRequest {
+            statsd_host: "statsd.internal".into(),
+            log_filter: "=[".into(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            update(request),
+            Err(ServerError::InvalidConfigUpdate(_))
+        ));
+        assert_eq!(get(), before);
+    }
+}
@@ -0,0 +1,877 @@
+//! Declarative changes to an SDP, for testing how the other side copes with
+//! what libwebrtc wouldn't create on its own.  The SDP is parsed into its
+//! session and media sections and the attributes the transforms look at, so a
+//! change only touches the lines it means to.
+
+use crate::error::{Result, ServerError};
+use crate::server::webrtc;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// A change to the media sections of an SDP, of every kind when `media` is None.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Transform {
+    /// Remove the codecs with these encoding names, and their retransmission
+    /// codecs.  The retransmission streams go too once no rtx codec is left.
+    RemoveCodecs {
+        media: Option<String>,
+        codecs: Vec<String>,
+    },
+    /// Replace the b=AS line, or only remove it when 0
+    SetBandwidth { media: Option<String>, kbps: u32 },
+    RemoveHeaderExtensions {
+        media: Option<String>,
+        uris: Vec<String>,
+    },
+    /// Strip the candidates of these types, or all of them when empty
+    StripCandidates {
+        media: Option<String>,
+        types: Vec<String>,
+    },
+}
+
+// the media kinds a transform can apply to, every kind when empty
+fn media_kind(media: String) -> Result<Option<String>> {
+    match media.as_str() {
+        "" => Ok(None),
+        "audio" | "video" | "application" => Ok(Some(media)),
+        _ => Err(ServerError::InvalidSdpTransform(format!(
+            "unknown media kind {}, expected audio, video or application",
+            media
+        ))),
+    }
+}
+
+impl Transform {
+    pub(crate) fn from_proto(transforms: Vec<webrtc::SdpTransform>) -> Result<Vec<Transform>> {
+        transforms.into_iter().map(Transform::try_from).collect()
+    }
+
+    fn media(&self) -> Option<&str> {
+        match self {
+            Transform::RemoveCodecs { media, .. }
+            | Transform::SetBandwidth { media, .. }
+            | Transform::RemoveHeaderExtensions { media, .. }
+            | Transform::StripCandidates { media, .. } => media.as_deref(),
+        }
+    }
+}
+
+impl TryFrom<webrtc::SdpTransform> for Transform {
+    type Error = ServerError;
+
+    fn try_from(transform: webrtc::SdpTransform) -> Result<Self> {
+        use webrtc::sdp_transform::Transform as Proto;
+
+        match transform.transform {
+            Some(Proto::RemoveCodecs(remove)) => Ok(Transform::RemoveCodecs {
+                media: media_kind(remove.media)?,
+                codecs: remove.codecs,
+            }),
+            Some(Proto::SetBandwidth(bandwidth)) => Ok(Transform::SetBandwidth {
+                media: media_kind(bandwidth.media)?,
+                kbps: bandwidth.kbps,
+            }),
+            Some(Proto::RemoveHeaderExtensions(remove)) => Ok(Transform::RemoveHeaderExtensions {
+                media: media_kind(remove.media)?,
+                uris: remove.uris,
+            }),
+            Some(Proto::StripCandidates(strip)) => Ok(Transform::StripCandidates {
+                media: media_kind(strip.media)?,
+                types: strip.types,
+            }),
+            None => Err(ServerError::InvalidSdpTransform(
+                "a transform must set one of its fields".into(),
+            )),
+        }
+    }
+}
+
+fn invalid_line(kind: char, value: &str) -> ServerError {
+    ServerError::InvalidSdpTransform(format!("invalid line {}={}", kind, value))
+}
+
+/// An `a=` line, the ones transforms change parsed into their fields.
+#[derive(Debug, Clone, PartialEq)]
+enum Attribute {
+    /// `a=rtpmap:<payload type> <encoding name>/<clock rate>[/<parameters>]`
+    Rtpmap {
+        payload_type: String,
+        encoding_name: String,
+        clock_rate: u32,
+        encoding_parameters: Option<String>,
+    },
+    /// `a=fmtp:<payload type> <parameter>;<parameter>`
+    Fmtp {
+        payload_type: String,
+        parameters: Vec<String>,
+    },
+    /// `a=rtcp-fb:<payload type or *> <feedback>`
+    RtcpFb {
+        payload_type: String,
+        feedback: String,
+    },
+    /// `a=extmap:<id>[/<direction>] <uri> [<attributes>]`
+    Extmap {
+        id: u16,
+        direction: Option<String>,
+        uri: String,
+        attributes: Option<String>,
+    },
+    /// `a=ssrc-group:<semantics> <ssrc>...`
+    SsrcGroup {
+        semantics: String,
+        ssrcs: Vec<u32>,
+    },
+    /// `a=ssrc:<ssrc> <attribute>[:<value>]`
+    Ssrc {
+        ssrc: u32,
+        attribute: String,
+    },
+    /// `a=candidate:<foundation> <component> <transport> <priority> <address> <port> typ <type> [<extension>...]`
+    Candidate {
+        foundation: String,
+        component: u32,
+        transport: String,
+        priority: u32,
+        address: String,
+        port: u16,
+        candidate_type: String,
+        extensions: Vec<String>,
+    },
+    Other {
+        name: String,
+        value: Option<String>,
+    },
+}
+
+impl Attribute {
+    fn parse(text: &str) -> Result<Self> {
+        let (name, value) = match text.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (text, None),
+        };
+
+        let attribute = match (name, value) {
+            ("rtpmap", Some(value)) => Attribute::rtpmap(value),
+            ("fmtp", Some(value)) => {
+                value
+                    .split_once(' ')
+                    .map(|(payload_type, parameters)| Attribute::Fmtp {
+                        payload_type: payload_type.into(),
+                        parameters: parameters.split(';').map(Into::into).collect(),
+                    })
+            }
+            ("rtcp-fb", Some(value)) => {
+                value
+                    .split_once(' ')
+                    .map(|(payload_type, feedback)| Attribute::RtcpFb {
+                        payload_type: payload_type.into(),
+                        feedback: feedback.into(),
+                    })
+            }
+            ("extmap", Some(value)) => Attribute::extmap(value),
+            ("ssrc-group", Some(value)) => Attribute::ssrc_group(value),
+            ("ssrc", Some(value)) => value.split_once(' ').and_then(|(ssrc, attribute)| {
+                Some(Attribute::Ssrc {
+                    ssrc: ssrc.parse().ok()?,
+                    attribute: attribute.into(),
+                })
+            }),
+            ("candidate", Some(value)) => Attribute::candidate(value),
+            (name, value) => Some(Attribute::Other {
+                name: name.into(),
+                value: value.map(Into::into),
+            }),
+        };
+
+        attribute.ok_or_else(|| invalid_line('a', text))
+    }
+
+    fn rtpmap(value: &str) -> Option<Self> {
+        let (payload_type, encoding) = value.split_once(' ')?;
+        let mut encoding = encoding.splitn(3, '/');
+
+        Some(Attribute::Rtpmap {
+            payload_type: payload_type.into(),
+            encoding_name: encoding.next()?.into(),
+            clock_rate: encoding.next()?.parse().ok()?,
+            encoding_parameters: encoding.next().map(Into::into),
+        })
+    }
+
+    fn extmap(value: &str) -> Option<Self> {
+        let (id, rest) = value.split_once(' ')?;
+        let (id, direction) = match id.split_once('/') {
+            Some((id, direction)) => (id, Some(direction.into())),
+            None => (id, None),
+        };
+        let (uri, attributes) = match rest.split_once(' ') {
+            Some((uri, attributes)) => (uri, Some(attributes.into())),
+            None => (rest, None),
+        };
+
+        Some(Attribute::Extmap {
+            id: id.parse().ok()?,
+            direction,
+            uri: uri.into(),
+            attributes,
+        })
+    }
+
+    fn ssrc_group(value: &str) -> Option<Self> {
+        let mut fields = value.split(' ');
+
+        Some(Attribute::SsrcGroup {
+            semantics: fields.next()?.into(),
+            ssrcs: fields
+                .map(|ssrc| ssrc.parse().ok())
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    fn candidate(value: &str) -> Option<Self> {
+        let mut fields = value.split(' ');
+        let foundation = fields.next()?.into();
+        let component = fields.next()?.parse().ok()?;
+        let transport = fields.next()?.into();
+        let priority = fields.next()?.parse().ok()?;
+        let address = fields.next()?.into();
+        let port = fields.next()?.parse().ok()?;
+
+        if fields.next()? != "typ" {
+            return None;
+        }
+
+        Some(Attribute::Candidate {
+            foundation,
+            component,
+            transport,
+            priority,
+            address,
+            port,
+            candidate_type: fields.next()?.into(),
+            extensions: fields.map(Into::into).collect(),
+        })
+    }
+
+    /// The payload type of the codec an rtpmap, fmtp or rtcp-fb line is about
+    fn payload_type(&self) -> Option<&str> {
+        match self {
+            Attribute::Rtpmap { payload_type, .. }
+            | Attribute::Fmtp { payload_type, .. }
+            | Attribute::RtcpFb { payload_type, .. } => Some(payload_type),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attribute::Rtpmap {
+                payload_type,
+                encoding_name,
+                clock_rate,
+                encoding_parameters,
+            } => {
+                write!(
+                    f,
+                    "rtpmap:{} {}/{}",
+                    payload_type, encoding_name, clock_rate
+                )?;
+                if let Some(parameters) = encoding_parameters {
+                    write!(f, "/{}", parameters)?;
+                }
+                Ok(())
+            }
+            Attribute::Fmtp {
+                payload_type,
+                parameters,
+            } => write!(f, "fmtp:{} {}", payload_type, parameters.join(";")),
+            Attribute::RtcpFb {
+                payload_type,
+                feedback,
+            } => write!(f, "rtcp-fb:{} {}", payload_type, feedback),
+            Attribute::Extmap {
+                id,
+                direction,
+                uri,
+                attributes,
+            } => {
+                write!(f, "extmap:{}", id)?;
+                if let Some(direction) = direction {
+                    write!(f, "/{}", direction)?;
+                }
+                write!(f, " {}", uri)?;
+                if let Some(attributes) = attributes {
+                    write!(f, " {}", attributes)?;
+                }
+                Ok(())
+            }
+            Attribute::SsrcGroup { semantics, ssrcs } => {
+                write!(f, "ssrc-group:{}", semantics)?;
+                for ssrc in ssrcs {
+                    write!(f, " {}", ssrc)?;
+                }
+                Ok(())
+            }
+            Attribute::Ssrc { ssrc, attribute } => write!(f, "ssrc:{} {}", ssrc, attribute),
+            Attribute::Candidate {
+                foundation,
+                component,
+                transport,
+                priority,
+                address,
+                port,
+                candidate_type,
+                extensions,
+            } => {
+                write!(
+                    f,
+                    "candidate:{} {} {} {} {} {} typ {}",
+                    foundation, component, transport, priority, address, port, candidate_type
+                )?;
+                for extension in extensions {
+                    write!(f, " {}", extension)?;
+                }
+                Ok(())
+            }
+            Attribute::Other { name, value } => match value {
+                Some(value) => write!(f, "{}:{}", name, value),
+                None => write!(f, "{}", name),
+            },
+        }
+    }
+}
+
+/// A `<type>=<value>` line of an SDP.
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    /// `b=<bwtype>:<bandwidth>`
+    Bandwidth {
+        bwtype: String,
+        bandwidth: u64,
+    },
+    Attribute(Attribute),
+    Other {
+        kind: char,
+        value: String,
+    },
+}
+
+impl Line {
+    fn parse(kind: char, value: &str) -> Result<Self> {
+        match kind {
+            'b' => value
+                .split_once(':')
+                .and_then(|(bwtype, bandwidth)| {
+                    Some(Line::Bandwidth {
+                        bwtype: bwtype.into(),
+                        bandwidth: bandwidth.parse().ok()?,
+                    })
+                })
+                .ok_or_else(|| invalid_line(kind, value)),
+            'a' => Attribute::parse(value).map(Line::Attribute),
+            _ => Ok(Line::Other {
+                kind,
+                value: value.into(),
+            }),
+        }
+    }
+
+    fn kind(&self) -> char {
+        match self {
+            Line::Bandwidth { .. } => 'b',
+            Line::Attribute(_) => 'a',
+            Line::Other { kind, .. } => *kind,
+        }
+    }
+
+    fn attribute(&self) -> Option<&Attribute> {
+        match self {
+            Line::Attribute(attribute) => Some(attribute),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Bandwidth { bwtype, bandwidth } => write!(f, "b={}:{}", bwtype, bandwidth),
+            Line::Attribute(attribute) => write!(f, "a={}", attribute),
+            Line::Other { kind, value } => write!(f, "{}={}", kind, value),
+        }
+    }
+}
+
+/// A media section, from its m= line to the next one.
+#[derive(Debug, Clone, PartialEq)]
+struct Media {
+    kind: String,
+    port: String,
+    proto: String,
+    formats: Vec<String>,
+    lines: Vec<Line>,
+}
+
+impl Media {
+    fn parse(value: &str) -> Result<Self> {
+        let mut fields = value.split_whitespace();
+
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(port), Some(proto)) => Ok(Media {
+                kind: kind.into(),
+                port: port.into(),
+                proto: proto.into(),
+                formats: fields.map(Into::into).collect(),
+                lines: vec![],
+            }),
+            _ => Err(invalid_line('m', value)),
+        }
+    }
+
+    fn attributes(&self) -> impl Iterator<Item = &Attribute> {
+        self.lines.iter().filter_map(Line::attribute)
+    }
+
+    fn remove_codecs(&mut self, codecs: &[String]) -> Result<()> {
+        let mut removed: HashSet<String> = self
+            .attributes()
+            .filter_map(|attribute| match attribute {
+                Attribute::Rtpmap {
+                    payload_type,
+                    encoding_name,
+                    ..
+                } if codecs
+                    .iter()
+                    .any(|codec| codec.eq_ignore_ascii_case(encoding_name)) =>
+                {
+                    Some(payload_type.clone())
+                }
+                _ => None,
+            })
+            .collect();
+
+        // retransmission codecs point at the codec they repair with apt=
+        let retransmissions: Vec<String> = self
+            .attributes()
+            .filter_map(|attribute| match attribute {
+                Attribute::Fmtp {
+                    payload_type,
+                    parameters,
+                } if parameters.iter().any(|parameter| {
+                    matches!(parameter.trim().split_once('='), Some(("apt", apt)) if removed.contains(apt))
+                }) =>
+                {
+                    Some(payload_type.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        removed.extend(retransmissions);
+
+        if !self.formats.is_empty() && self.formats.iter().all(|f| removed.contains(f)) {
+            return Err(ServerError::InvalidSdpTransform(format!(
+                "removing {} would leave the {} section without codecs",
+                codecs.join(", "),
+                self.kind
+            )));
+        }
+
+        self.formats.retain(|format| !removed.contains(format));
+        self.lines.retain(|line| {
+            !matches!(
+                line.attribute().and_then(Attribute::payload_type),
+                Some(payload_type) if removed.contains(payload_type)
+            )
+        });
+
+        if !self.has_retransmissions() {
+            self.remove_retransmission_streams();
+        }
+
+        Ok(())
+    }
+
+    fn has_retransmissions(&self) -> bool {
+        self.attributes().any(|attribute| {
+            matches!(
+                attribute,
+                Attribute::Rtpmap { encoding_name, .. } if encoding_name.eq_ignore_ascii_case("rtx")
+            )
+        })
+    }
+
+    // a=ssrc-group:FID <ssrc> <rtx ssrc> pairs a stream with its retransmissions
+    fn remove_retransmission_streams(&mut self) {
+        let ssrcs: HashSet<u32> = self
+            .attributes()
+            .filter_map(|attribute| match attribute {
+                Attribute::SsrcGroup { semantics, ssrcs } if semantics == "FID" => Some(ssrcs),
+                _ => None,
+            })
+            .flat_map(|ssrcs| ssrcs.iter().skip(1).copied())
+            .collect();
+
+        self.lines.retain(|line| match line.attribute() {
+            Some(Attribute::SsrcGroup { semantics, .. }) => semantics != "FID",
+            Some(Attribute::Ssrc { ssrc, .. }) => !ssrcs.contains(ssrc),
+            _ => true,
+        });
+    }
+
+    fn set_bandwidth(&mut self, kbps: u32) {
+        self.lines
+            .retain(|line| !matches!(line, Line::Bandwidth { bwtype, .. } if bwtype == "AS"));
+
+        if kbps > 0 {
+            // b= lines follow the i= and c= lines of a media section
+            let position = self
+                .lines
+                .iter()
+                .position(|line| !matches!(line.kind(), 'i' | 'c'))
+                .unwrap_or(self.lines.len());
+            self.lines.insert(
+                position,
+                Line::Bandwidth {
+                    bwtype: "AS".into(),
+                    bandwidth: kbps.into(),
+                },
+            );
+        }
+    }
+
+    fn remove_header_extensions(&mut self, uris: &[String]) {
+        self.lines.retain(|line| match line.attribute() {
+            Some(Attribute::Extmap { uri, .. }) => !uris.contains(uri),
+            _ => true,
+        });
+    }
+
+    fn strip_candidates(&mut self, types: &[String]) {
+        self.lines.retain(|line| match line.attribute() {
+            Some(Attribute::Candidate { candidate_type, .. }) => {
+                !(types.is_empty() || types.contains(candidate_type))
+            }
+            _ => true,
+        });
+    }
+}
+
+impl fmt::Display for Media {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m={} {} {}", self.kind, self.port, self.proto)?;
+
+        for format in &self.formats {
+            write!(f, " {}", format)?;
+        }
+        write!(f, "\r\n")?;
+
+        for line in &self.lines {
+            write!(f, "{}\r\n", line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An SDP split into its session description and media sections.
+#[derive(Debug, Clone, PartialEq)]
+struct Sdp {
+    session: Vec<Line>,
+    media: Vec<Media>,
+}
+
+impl Sdp {
+    fn parse(sdp: &str) -> Result<Self> {
+        let mut parsed = Sdp {
+            session: vec![],
+            media: vec![],
+        };
+
+        for (number, text) in sdp.lines().enumerate() {
+            if text.is_empty() {
+                continue;
+            }
+
+            let (kind, value) = match text.split_once('=') {
+                Some((kind, value))
+                    if kind.len() == 1 && kind.as_bytes()[0].is_ascii_lowercase() =>
+                {
+                    (kind.as_bytes()[0] as char, value)
+                }
+                _ => {
+                    return Err(ServerError::InvalidSdpTransform(format!(
+                        "line {} of the SDP isn't a <type>=<value> line: {}",
+                        number + 1,
+                        text
+                    )))
+                }
+            };
+
+            match (kind, parsed.media.last_mut()) {
+                ('m', _) => parsed.media.push(Media::parse(value)?),
+                (_, Some(media)) => media.lines.push(Line::parse(kind, value)?),
+                (_, None) => parsed.session.push(Line::parse(kind, value)?),
+            }
+        }
+
+        if !matches!(parsed.session.first(), Some(line) if line.kind() == 'v') {
+            return Err(ServerError::InvalidSdpTransform(
+                "the SDP must start with a v= line".into(),
+            ));
+        }
+
+        Ok(parsed)
+    }
+
+    fn apply(&mut self, transform: &Transform) -> Result<()> {
+        let sections = self
+            .media
+            .iter_mut()
+            .filter(|media| match transform.media() {
+                Some(kind) => kind == media.kind,
+                None => true,
+            });
+
+        for media in sections {
+            match transform {
+                Transform::RemoveCodecs { codecs, .. } => media.remove_codecs(codecs)?,
+                Transform::SetBandwidth { kbps, .. } => media.set_bandwidth(*kbps),
+                Transform::RemoveHeaderExtensions { uris, .. } => {
+                    media.remove_header_extensions(uris)
+                }
+                Transform::StripCandidates { types, .. } => media.strip_candidates(types),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Sdp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.session {
+            write!(f, "{}\r\n", line)?;
+        }
+
+        for media in &self.media {
+            write!(f, "{}", media)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Apply the transforms in order, an SDP without any is returned untouched.
+pub(crate) fn apply(sdp: String, transforms: &[Transform]) -> Result<String> {
+    if transforms.is_empty() {
+        return Ok(sdp);
+    }
+
+    let mut parsed = Sdp::parse(&sdp)?;
+
+    for transform in transforms {
+        parsed.apply(transform)?;
+    }
+
+    Ok(parsed.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0 1\r\n\
+m=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=candidate:1 1 udp 2122260223 192.168.1.2 54400 typ host generation 0\r\n\
+a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n\
+a=mid:0\r\n\
+a=rtpmap:111 opus/48000/2\r\n\
+a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+a=rtpmap:0 PCMU/8000\r\n\
+m=video 9 UDP/TLS/RTP/SAVPF 96 97 98\r\n\
+c=IN IP4 0.0.0.0\r\n\
+b=AS:2000\r\n\
+a=candidate:1 1 udp 2122260223 192.168.1.2 54401 typ host generation 0\r\n\
+a=candidate:2 1 udp 1686052607 203.0.113.7 54401 typ srflx raddr 192.168.1.2 rport 54401\r\n\
+a=extmap:2 urn:ietf:params:rtp-hdrext:toffset\r\n\
+a=extmap:3 http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time\r\n\
+a=mid:1\r\n\
+a=rtpmap:96 VP8/90000\r\n\
+a=rtcp-fb:96 nack\r\n\
+a=rtpmap:97 rtx/90000\r\n\
+a=fmtp:97 apt=96\r\n\
+a=rtpmap:98 H264/90000\r\n\
+a=rtcp-fb:* transport-cc\r\n\
+a=ssrc-group:FID 1111 2222\r\n\
+a=ssrc:1111 cname:load\r\n\
+a=ssrc:2222 cname:load\r\n";
+
+    fn transformed(transforms: &[Transform]) -> String {
+        apply(OFFER.into(), transforms).unwrap()
+    }
+
+    #[test]
+    fn it_reads_transforms_from_json() {
+        let transform: webrtc::SdpTransform =
+            serde_json::from_str(r#"{"setBandwidth": {"media": "video", "kbps": 500}}"#).unwrap();
+        let transforms = Transform::from_proto(vec![transform]).unwrap();
+
+        assert_eq!(
+            vec![Transform::SetBandwidth {
+                media: Some("video".into()),
+                kbps: 500
+            }],
+            transforms
+        );
+        let empty: webrtc::SdpTransform = serde_json::from_str("{}").unwrap();
+        assert!(Transform::from_proto(vec![empty]).is_err());
+
+        let unknown_media: webrtc::SdpTransform =
+            serde_json::from_str(r#"{"setBandwidth": {"media": "vidoe", "kbps": 500}}"#).unwrap();
+        assert!(matches!(
+            Transform::from_proto(vec![unknown_media]),
+            Err(ServerError::InvalidSdpTransform(_))
+        ));
+    }
+
+    #[test]
+    fn it_round_trips_an_sdp() {
+        assert_eq!(OFFER, Sdp::parse(OFFER).unwrap().to_string());
+        assert_eq!(OFFER, transformed(&[]));
+        assert!(apply(
+            "m=audio 9 RTP/AVP 0".into(),
+            &[Transform::SetBandwidth {
+                media: None,
+                kbps: 1
+            }]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn it_parses_attributes() {
+        assert_eq!(
+            Attribute::Rtpmap {
+                payload_type: "111".into(),
+                encoding_name: "opus".into(),
+                clock_rate: 48000,
+                encoding_parameters: Some("2".into()),
+            },
+            Attribute::parse("rtpmap:111 opus/48000/2").unwrap()
+        );
+        assert_eq!(
+            Attribute::Extmap {
+                id: 4,
+                direction: Some("sendonly".into()),
+                uri: "urn:example".into(),
+                attributes: None,
+            },
+            Attribute::parse("extmap:4/sendonly urn:example").unwrap()
+        );
+        assert!(matches!(
+            Attribute::parse("candidate:2 1 udp 1686052607 203.0.113.7 54401 typ srflx raddr 192.168.1.2 rport 54401").unwrap(),
+            Attribute::Candidate { candidate_type, extensions, .. }
+                if candidate_type == "srflx" && extensions.len() == 4
+        ));
+        assert_eq!(
+            Attribute::Other {
+                name: "rtcp-mux".into(),
+                value: None
+            },
+            Attribute::parse("rtcp-mux").unwrap()
+        );
+
+        for malformed in &["rtpmap:96 VP8", "ssrc:abc cname:load", "candidate:1 1 udp"] {
+            assert!(matches!(
+                Attribute::parse(malformed),
+                Err(ServerError::InvalidSdpTransform(_))
+            ));
+        }
+        assert!(Line::parse('b', "AS:lots").is_err());
+    }
+
+    #[test]
+    fn it_removes_codecs_with_their_retransmissions() {
+        let sdp = transformed(&[Transform::RemoveCodecs {
+            media: Some("video".into()),
+            codecs: vec!["vp8".into()],
+        }]);
+
+        assert!(sdp.contains("m=video 9 UDP/TLS/RTP/SAVPF 98\r\n"));
+        assert!(!sdp.contains("a=rtpmap:96") && !sdp.contains("a=rtcp-fb:96"));
+        assert!(!sdp.contains("a=rtpmap:97") && !sdp.contains("a=fmtp:97"));
+        assert!(sdp.contains("a=rtcp-fb:* transport-cc"));
+        assert!(sdp.contains("m=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\n"));
+        assert!(!sdp.contains("a=ssrc-group:FID") && !sdp.contains("a=ssrc:2222"));
+        assert!(sdp.contains("a=ssrc:1111 cname:load"));
+
+        // the retransmissions of the remaining codecs keep their stream
+        let h264 = transformed(&[Transform::RemoveCodecs {
+            media: Some("video".into()),
+            codecs: vec!["H264".into()],
+        }]);
+        assert!(h264.contains("a=ssrc-group:FID 1111 2222") && h264.contains("a=ssrc:2222"));
+
+        let every_codec = Transform::RemoveCodecs {
+            media: None,
+            codecs: vec!["opus".into(), "PCMU".into()],
+        };
+        assert!(apply(OFFER.into(), &[every_codec]).is_err());
+    }
+
+    #[test]
+    fn it_sets_the_bandwidth_of_media_sections() {
+        let sdp = transformed(&[Transform::SetBandwidth {
+            media: None,
+            kbps: 500,
+        }]);
+
+        assert!(
+            sdp.contains("m=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\nc=IN IP4 0.0.0.0\r\nb=AS:500\r\n")
+        );
+        assert!(sdp.contains("c=IN IP4 0.0.0.0\r\nb=AS:500\r\na=candidate"));
+        assert!(!sdp.contains("b=AS:2000"));
+        assert_eq!(2, sdp.matches("b=AS:").count());
+
+        let removed = transformed(&[Transform::SetBandwidth {
+            media: Some("video".into()),
+            kbps: 0,
+        }]);
+        assert!(!removed.contains("b=AS"));
+    }
+
+    #[test]
+    fn it_removes_header_extensions_and_candidates() {
+        let sdp = transformed(&[
+            Transform::RemoveHeaderExtensions {
+                media: None,
+                uris: vec!["http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time".into()],
+            },
+            Transform::StripCandidates {
+                media: Some("video".into()),
+                types: vec!["srflx".into()],
+            },
+        ]);
+
+        assert!(!sdp.contains("abs-send-time"));
+        assert!(sdp.contains("urn:ietf:params:rtp-hdrext:toffset"));
+        assert!(!sdp.contains("typ srflx"));
+        assert_eq!(2, sdp.matches("typ host").count());
+
+        let stripped = transformed(&[Transform::StripCandidates {
+            media: None,
+            types: vec![],
+        }]);
+        assert!(!stripped.contains("a=candidate"));
+    }
+}
@@ -1,24 +1,101 @@
+use crate::auth::Authenticator;
+use crate::config::CONFIG;
 use crate::data::SharedState;
-use crate::error::{Result, ServerError};
-use log::info;
-use tonic::transport::Server;
+use crate::error::{
+    Result, ServerError, PEER_CONNECTION_ID_METADATA, SESSION_ID_METADATA,
+    WEBRTC_ERROR_KIND_METADATA,
+};
+use crate::health::proto::health_server::HealthServer;
+use crate::health::HealthService;
+use futures::Future;
+use std::time::Duration;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tracing::{field, info, info_span, Span};
 use webrtc::web_rtc_server::WebRtcServer;
 
 pub(crate) mod webrtc {
     tonic::include_proto!("webrtc");
 }
 
-pub(crate) async fn serve(addr: &str, shared_state: SharedState) -> Result<()> {
+pub(crate) async fn serve(
+    addr: &str,
+    shared_state: SharedState,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
     let addr = addr.parse()?;
-    let service = WebRtcServer::new(shared_state);
+    // health checks are unauthenticated so probes don't need a token
+    let health_service = HealthServer::new(HealthService::new(shared_state.data.clone()));
+    let service = WebRtcServer::with_interceptor(shared_state, Authenticator);
+    let service = grpc_web_config().enable(service);
+    let mut builder = Server::builder();
+
+    if let Some(tls_config) = tls_config()? {
+        builder = builder
+            .tls_config(tls_config)
+            .map_err(|e| ServerError::InvalidConfig(e.to_string()))?;
+    }
 
     info!("Starting gPRC service on {:?}", addr);
 
-    Server::builder()
+    // gRPC-Web requests from browsers are sent over HTTP/1.1
+    builder
+        .accept_http1(true)
+        .trace_fn(|request| rpc_span(request.uri().path()))
+        .add_service(health_service)
         .add_service(service)
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown)
         .await
         .map_err(|e| ServerError::InternalError(e.to_string()))?;
 
     Ok(())
 }
+
+/// The span of an RPC, its ids are recorded once the request is decoded.
+pub(crate) fn rpc_span(path: &str) -> Span {
+    info_span!(
+        "rpc",
+        path,
+        session_id = field::Empty,
+        peer_connection_id = field::Empty
+    )
+}
+
+pub(crate) fn read_pem(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| ServerError::InvalidConfig(format!("{}: {}", path, e)))
+}
+
+/// Serve TLS when a certificate and key are configured, requiring client
+/// certificates when a client CA is also configured.
+fn tls_config() -> Result<Option<ServerTlsConfig>> {
+    // both or neither are set in a validated config
+    let (cert_path, key_path) = match (&CONFIG.tls_cert_path, &CONFIG.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Ok(None),
+    };
+    let identity = Identity::from_pem(read_pem(cert_path)?, read_pem(key_path)?);
+    let mut tls_config = ServerTlsConfig::new().identity(identity);
+
+    if let Some(client_ca_path) = &CONFIG.tls_client_ca_path {
+        let client_ca = Certificate::from_pem(read_pem(client_ca_path)?);
+        tls_config = tls_config.client_ca_root(client_ca);
+    }
+
+    Ok(Some(tls_config))
+}
+
+/// Browsers can't speak HTTP/2 gRPC, so the service also accepts gRPC-Web,
+/// restricted to the origins in the config.
+fn grpc_web_config() -> tonic_web::Config {
+    let config = match &CONFIG.cors_allowed_origins {
+        Some(origins) => tonic_web::config().allow_origins(origins.clone()),
+        None => tonic_web::config().allow_all_origins(),
+    };
+
+    config
+        .max_age(CONFIG.cors_max_age_s.map(Duration::from_secs))
+        .expose_headers(vec![
+            SESSION_ID_METADATA,
+            PEER_CONNECTION_ID_METADATA,
+            WEBRTC_ERROR_KIND_METADATA,
+        ])
+}
@@ -1,17 +1,32 @@
+use crate::auth::Token;
+use crate::collector::StatsTicks;
+use crate::config::CONFIG;
 use crate::error::{Result, ServerError};
-use crate::helpers::elapsed;
-use crate::log::LogLevel;
-use crate::peer_connection::{PeerConnectionManager, VideoReceiveState, VideoSendState};
-// use crate::stats::{get_peer_connection_stats, get_stats, PeerConnectionStats, Stats};
-use crate::stats::{get_stats, Stats};
+use crate::helpers::{elapsed, percentile};
+use crate::idempotency::IdempotencyKeys;
+use crate::lifecycle::{setup_latencies, Lifecycle, SetupLatency};
+use crate::log::{LogLevel, SessionLogs};
+use crate::media_detection::DetectionRules;
+use crate::peer_connection::{
+    FrameProducer, PeerConnectionManager, VideoReceiveState, VideoSendState,
+};
+use crate::report::PeerConnectionRecord;
+use crate::runtime_config;
+use crate::session_log;
+use crate::slo::{Observed, Thresholds, Verdict};
+use crate::stats::{get_stats, QualityLimitedSenders, Stats};
 use crate::webrtc_pool::WebRTCPool;
 use core::fmt;
+use dashmap::mapref::entry::Entry;
 use dashmap::mapref::one::Ref;
 use dashmap::DashMap;
-use libwebrtc::empty_frame_producer::EmptyFrameProducer;
+use libwebrtc::peer_connection::PeerConnection;
+use libwebrtc::peer_connection_observer::ConnectionState;
 use libwebrtc::video_track_source::VideoTrackSource;
-use log::{error, info};
+use parking_lot::Mutex;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
+use tracing::{instrument, Level};
 
 pub(crate) type PeerConnections = DashMap<String, PeerConnectionManager>;
 
@@ -24,6 +39,12 @@ impl From<PeerConnectionState> for crate::server::webrtc::PeerConnectionState {
             num_not_sending: peer_connection_state.num_not_sending,
             num_receiving: peer_connection_state.num_receiving,
             num_not_receiving: peer_connection_state.num_not_receiving,
+            send_stalls: peer_connection_state.send_stalls,
+            receive_stalls: peer_connection_state.receive_stalls,
+            max_time_to_first_send_ms: as_millis(peer_connection_state.max_time_to_first_send),
+            max_time_to_first_receive_ms: as_millis(
+                peer_connection_state.max_time_to_first_receive,
+            ),
         }
     }
 }
@@ -32,42 +53,89 @@ impl From<PeerConnectionState> for crate::server::webrtc::PeerConnectionState {
 pub(crate) enum SessionState {
     Created,
     Started,
+    Paused,
     Stopped,
 }
 
+impl std::str::FromStr for SessionState {
+    type Err = ServerError;
+
+    fn from_str(state: &str) -> Result<Self> {
+        match state.to_ascii_lowercase().as_str() {
+            "created" => Ok(SessionState::Created),
+            "started" => Ok(SessionState::Started),
+            "paused" => Ok(SessionState::Paused),
+            "stopped" => Ok(SessionState::Stopped),
+            _ => Err(ServerError::ParseError(format!(
+                "unknown session state {}",
+                state
+            ))),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct PeerConnectionState {
     num_sending: i32,
     num_not_sending: i32,
     num_receiving: i32,
     num_not_receiving: i32,
+    send_stalls: u32,
+    receive_stalls: u32,
+    max_time_to_first_send: Option<Duration>,
+    max_time_to_first_receive: Option<Duration>,
+}
+
+fn as_millis(duration: Option<Duration>) -> u64 {
+    duration.map_or(0, |duration| duration.as_millis() as u64)
 }
 
 pub(crate) struct Session {
     pub(crate) id: String,
     pub(crate) name: String,
     pub(crate) peer_connections: PeerConnections,
+    // the lifecycles of the peer connections of the current run, kept once they're closed
+    pub(crate) lifecycles: DashMap<String, Arc<Lifecycle>>,
+    // what the current run saw of its peer connections, for its report
+    pub(crate) records: DashMap<String, PeerConnectionRecord>,
+    // peer connection ids, keyed by the idempotency key of the create request
+    pub(crate) idempotency_keys: IdempotencyKeys,
+    // the sender and receiver ids of loopback pairs, keyed the same way
+    pub(crate) loopback_keys: IdempotencyKeys<(String, String)>,
     pub(crate) video_source: VideoTrackSource,
     pub(crate) polling_state_s: Duration,
+    // None follows the server's stats interval
+    pub(crate) stats_interval: Option<Duration>,
+    pub(crate) stats_ticks: StatsTicks,
+    pub(crate) media_detection: DetectionRules,
+    pub(crate) thresholds: Option<Thresholds>,
+    // of the current run
+    pub(crate) verdict: Mutex<Verdict>,
     pub(crate) log_level: LogLevel,
+    pub(crate) logs: SessionLogs,
     pub(crate) state: SessionState,
     pub(crate) start_time: Option<SystemTime>,
     pub(crate) stop_time: Option<SystemTime>,
+    pub(crate) run: u32,
+    // the bearer token of the request that created the session
+    pub(crate) token: Option<Token>,
     pub(crate) webrtc_pool: WebRTCPool,
-    frame_producer: EmptyFrameProducer,
+    frame_producer: FrameProducer,
 }
 
 impl fmt::Debug for Session {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "id={}, name={}, num_peer_connections={}, state={:?}, polling_state_s={:?}, log_level={:?}, start_time={:?}, stop_time={:?}",
+            "id={}, name={}, num_peer_connections={}, state={:?}, polling_state_s={:?}, stats_interval={:?}, log_level={:?}, run={}, start_time={:?}, stop_time={:?}",
             self.id,
             self.name,
             self.peer_connections.len(),
             self.state,
             self.polling_state_s,
+            self.stats_interval(),
             self.log_level,
+            self.run,
             self.start_time,
             self.stop_time
         )
@@ -81,72 +149,188 @@ impl Session {
         polling_state_s: Duration,
         log_level: LogLevel,
     ) -> Result<Self> {
-        LogLevel::set_log_level(&log_level);
         let peer_connections: PeerConnections = DashMap::new();
-        let (video_source, frame_producer) = PeerConnectionManager::file_video_source()?;
-        let webrtc_pool = WebRTCPool::new(num_cpus::get())?;
+        let (video_source, mut frame_producer) = PeerConnectionManager::file_video_source()?;
+        // frames only flow while the session is started
+        frame_producer.cancel();
+        let webrtc_pool = WebRTCPool::new(CONFIG.factory_count)?;
+        let logs = SessionLogs::new(id.clone(), log_level);
 
         Ok(Self {
             id,
             name,
             peer_connections,
+            lifecycles: DashMap::new(),
+            records: DashMap::new(),
+            idempotency_keys: IdempotencyKeys::default(),
+            loopback_keys: IdempotencyKeys::default(),
             video_source,
             state: SessionState::Created,
             polling_state_s,
+            stats_interval: None,
+            stats_ticks: StatsTicks::default(),
+            media_detection: DetectionRules::default(),
+            thresholds: None,
+            verdict: Mutex::new(Verdict::default()),
             log_level,
+            logs,
             start_time: None,
             stop_time: None,
+            run: 0,
+            token: None,
             frame_producer,
             webrtc_pool,
         })
     }
 
+    /// Start a created session, or restart a stopped session into a new run.
+    #[instrument(skip_all, fields(session_id = %self.id))]
     pub(crate) fn start(&mut self) -> Result<()> {
-        info!("Attempting to start session {}", self.id);
+        session_log!(
+            self.logs,
+            Level::INFO,
+            None,
+            "Attempting to start session {}",
+            self.id
+        );
+
+        match self.state {
+            SessionState::Created => {}
+            SessionState::Stopped => {
+                self.lifecycles.clear();
+                self.records.clear();
+            }
+            _ => {
+                return Err(ServerError::InvalidStateError(
+                    "Only a created or stopped session can be started".into(),
+                ))
+            }
+        }
+
+        self.frame_producer.resume()?;
+
+        self.state = SessionState::Started;
+        self.run += 1;
+        self.stats_ticks = StatsTicks::default();
+        *self.verdict.get_mut() = Verdict::default();
+        self.start_time = Some(SystemTime::now());
+        self.stop_time = None;
+
+        session_log!(self.logs, Level::INFO, None, "Started session: {:?}", self);
+
+        Ok(())
+    }
+
+    /// Halt frame production and stats polling, keeping the peer connections.
+    #[instrument(skip_all, fields(session_id = %self.id))]
+    pub(crate) fn pause(&mut self) -> Result<()> {
+        session_log!(
+            self.logs,
+            Level::INFO,
+            None,
+            "Attempting to pause session {}",
+            self.id
+        );
 
-        if self.state != SessionState::Created {
+        if self.state != SessionState::Started {
             return Err(ServerError::InvalidStateError(
-                "Only a created session can be started".into(),
+                "Only a started session can be paused".into(),
             ));
         }
 
+        self.frame_producer.cancel();
+        self.state = SessionState::Paused;
+
+        session_log!(self.logs, Level::INFO, None, "Paused session: {:?}", self);
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(session_id = %self.id))]
+    pub(crate) fn resume(&mut self) -> Result<()> {
+        session_log!(
+            self.logs,
+            Level::INFO,
+            None,
+            "Attempting to resume session {}",
+            self.id
+        );
+
+        if self.state != SessionState::Paused {
+            return Err(ServerError::InvalidStateError(
+                "Only a paused session can be resumed".into(),
+            ));
+        }
+
+        self.frame_producer.resume()?;
         self.state = SessionState::Started;
-        self.start_time = Some(SystemTime::now());
 
-        info!("Started session: {:?}", self);
+        session_log!(self.logs, Level::INFO, None, "Resumed session: {:?}", self);
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(session_id = %self.id))]
     pub(crate) fn stop(&mut self) -> Result<()> {
-        info!("Attempting to stop session {}", self.id);
+        session_log!(
+            self.logs,
+            Level::INFO,
+            None,
+            "Attempting to stop session {}",
+            self.id
+        );
 
-        if self.state != SessionState::Started {
+        if self.state != SessionState::Started && self.state != SessionState::Paused {
             return Err(ServerError::InvalidStateError(
-                "Only a started session can be stopped".into(),
+                "Only a started or paused session can be stopped".into(),
             ));
         }
 
+        // a final check, while the stats of the peer connections are still around
+        self.evaluate_thresholds();
+        self.frame_producer.cancel();
+        self.close_peer_connections();
         self.state = SessionState::Stopped;
         self.stop_time = Some(SystemTime::now());
-        LogLevel::set_log_level(&LogLevel::default());
 
-        info!("stopped session: {:?}", self);
-
-        #[allow(clippy::drop_ref)]
-        drop(self);
+        session_log!(self.logs, Level::INFO, None, "stopped session: {:?}", self);
 
         Ok(())
     }
 
-    pub(crate) async fn export_peer_connection_stats(&self, should_poll_state: bool) {
-        for mut pc in self.peer_connections.iter_mut() {
-            pc.value_mut()
-                .export_stats(&self.id, should_poll_state)
-                .await
-                .map_err(|e| error!("Failed to export stats for peer connection: {}", e))
-                .ok();
+    // Drop every peer connection, which closes it in libwebrtc
+    fn close_peer_connections(&self) {
+        self.peer_connections.iter().for_each(|pc| {
+            self.webrtc_pool
+                .release_peer_connection_manager(pc.value().pool_id)
+        });
+        self.peer_connections.clear();
+
+        session_log!(
+            self.logs,
+            Level::INFO,
+            None,
+            "Closed all peer connections for session {}",
+            self.id
+        );
+    }
+
+    /// Peer connections can't be added or negotiated once a session is stopped.
+    pub(crate) fn ensure_not_stopped(&self) -> Result<()> {
+        if self.state == SessionState::Stopped {
+            return Err(ServerError::InvalidStateError(format!(
+                "Session {} is stopped",
+                self.id
+            )));
         }
+
+        Ok(())
+    }
+
+    /// The interval between stats exports, the server's unless the session set one
+    pub(crate) fn stats_interval(&self) -> Duration {
+        self.stats_interval
+            .unwrap_or_else(runtime_config::stats_interval)
     }
 
     // Tally the states of all of the peer connections
@@ -162,76 +346,295 @@ impl Session {
                 VideoReceiveState::Receiving(_) => peer_connection_state.num_receiving += 1,
                 VideoReceiveState::NotReceiving(_) => peer_connection_state.num_not_receiving += 1,
             };
+
+            let (send, receive) = (&pc.value().state.send, &pc.value().state.receive);
+            peer_connection_state.send_stalls += send.stalls;
+            peer_connection_state.receive_stalls += receive.stalls;
+            peer_connection_state.max_time_to_first_send = peer_connection_state
+                .max_time_to_first_send
+                .max(send.time_to_first_media);
+            peer_connection_state.max_time_to_first_receive = peer_connection_state
+                .max_time_to_first_receive
+                .max(receive.time_to_first_media);
         });
 
         peer_connection_state
     }
 
+    /// The outbound streams of the open peer connections by what limits their quality
+    pub(crate) fn quality_limited_senders(&self) -> QualityLimitedSenders {
+        let mut senders = QualityLimitedSenders::default();
+
+        self.peer_connections
+            .iter()
+            .for_each(|pc| senders.merge(&pc.value().sample.senders));
+
+        senders
+    }
+
+    #[instrument(skip_all, fields(session_id = %self.id))]
     pub(crate) async fn get_stats(&self) -> Result<Stats> {
-        info!("Attempting to get stats for session {}", self.id);
+        session_log!(
+            self.logs,
+            Level::INFO,
+            None,
+            "Attempting to get stats for session {}",
+            self.id
+        );
 
         let stats = get_stats(self).await?;
 
-        info!("Stats for session {}: {:?}", self.id, stats);
+        session_log!(
+            self.logs,
+            Level::INFO,
+            None,
+            "Stats for session {}: {:?}",
+            self.id,
+            stats
+        );
 
         Ok(stats)
     }
 
+    #[instrument(skip_all, fields(session_id = %self.id))]
     pub(crate) fn add_peer_connection(&self, peer_connection: PeerConnectionManager) -> Result<()> {
-        info!(
+        session_log!(
+            self.logs,
+            Level::INFO,
+            Some(&peer_connection.id),
             "Attempting to add peer connection {} for session {}",
-            peer_connection.id, self.id
+            peer_connection.id,
+            self.id
         );
+        self.ensure_not_stopped()?;
         let peer_connection_id = peer_connection.id.clone();
 
-        self.peer_connections
-            .insert(peer_connection_id.clone(), peer_connection);
+        match self.peer_connections.entry(peer_connection_id.clone()) {
+            Entry::Occupied(_) => {
+                self.webrtc_pool
+                    .release_peer_connection_manager(peer_connection.pool_id);
+                return Err(ServerError::PeerConnectionAlreadyExists(peer_connection_id));
+            }
+            Entry::Vacant(entry) => {
+                self.lifecycles.insert(
+                    peer_connection_id.clone(),
+                    peer_connection.lifecycle.clone(),
+                );
+                self.records.insert(
+                    peer_connection_id.clone(),
+                    PeerConnectionRecord::new(peer_connection.name.clone()),
+                );
+                entry.insert(peer_connection);
+            }
+        }
 
-        info!(
+        session_log!(
+            self.logs,
+            Level::INFO,
+            Some(&peer_connection_id),
             "Added peer connection {} to session {}",
-            &peer_connection_id, &self.id
+            &peer_connection_id,
+            &self.id
         );
 
         Ok(())
     }
 
+    pub(crate) fn list_peer_connections(&self) -> Vec<crate::server::webrtc::PeerConnectionInfo> {
+        let mut peer_connections: Vec<crate::server::webrtc::PeerConnectionInfo> = self
+            .peer_connections
+            .iter()
+            .map(|pc| pc.value().into())
+            .collect();
+        peer_connections.sort_by(|a, b| a.id.cmp(&b.id));
+
+        peer_connections
+    }
+
+    pub(crate) fn ensure_peer_connection_id_available(&self, id: &str) -> Result<()> {
+        if self.peer_connections.contains_key(id) {
+            return Err(ServerError::PeerConnectionAlreadyExists(id.into()));
+        }
+
+        Ok(())
+    }
+
+    /// Create a sender and a receiver peer connection negotiated with each
+    /// other, so media flows without any external SFU.
+    #[instrument(skip_all, fields(session_id = %self.id))]
+    pub(crate) async fn create_loopback_pair(
+        &self,
+        sender_id: String,
+        receiver_id: String,
+        name: String,
+    ) -> Result<()> {
+        session_log!(
+            self.logs,
+            Level::INFO,
+            Some(&sender_id),
+            "Attempting to create loopback pair {} -> {} for session {}",
+            sender_id,
+            receiver_id,
+            self.id
+        );
+        self.ensure_not_stopped()?;
+        self.ensure_peer_connection_id_available(&sender_id)?;
+        self.ensure_peer_connection_id_available(&receiver_id)?;
+
+        if sender_id == receiver_id {
+            return Err(ServerError::PeerConnectionAlreadyExists(receiver_id));
+        }
+
+        let mut sender = self
+            .webrtc_pool
+            .create_peer_connection_manager(sender_id, format!("{} (sender)", name))?;
+        let mut receiver = self
+            .webrtc_pool
+            .create_peer_connection_manager(receiver_id, format!("{} (receiver)", name))?;
+        sender.watch_events();
+        receiver.watch_events();
+
+        sender
+            .add_track(&self.webrtc_pool, &self.video_source, nanoid::nanoid!())
+            .await?;
+        sender.connect_loopback(&mut receiver).await?;
+
+        self.add_peer_connection(sender)?;
+        self.add_peer_connection(receiver)?;
+
+        Ok(())
+    }
+
     pub(crate) fn get_peer_connection(
         &self,
         id: &str,
     ) -> Result<Ref<String, PeerConnectionManager>> {
-        info!(
+        session_log!(
+            self.logs,
+            Level::INFO,
+            Some(id),
             "Attempting to get peer connection {} for session {}",
-            id, self.id
+            id,
+            self.id
         );
+        self.ensure_not_stopped()?;
 
-        let value = self.peer_connections.get(id).ok_or_else(|| {
-            ServerError::InvalidPeerConnection(format!("Peer connection {} not found", id))
-        })?;
+        let value = self
+            .peer_connections
+            .get(id)
+            .ok_or_else(|| ServerError::InvalidPeerConnection(id.into()))?;
         Ok(value)
     }
 
-    // pub(crate) async fn get_peer_connection_stats(&self, id: &str) -> Result<PeerConnectionStats> {
-    //     info!(
-    //         "Attempting to get peer connection stats for session {} pc {}",
-    //         self.id, id
-    //     );
+    /// The libwebrtc peer connections to fetch stats from
+    pub(crate) fn webrtc_peer_connections(&self) -> Vec<(String, Arc<PeerConnection>)> {
+        self.peer_connections
+            .iter()
+            .map(|pc| (pc.id.clone(), pc.webrtc_peer_connection.clone()))
+            .collect()
+    }
+
+    /// The values the thresholds are checked against, from the latest stats.
+    pub(crate) fn observe(&self) -> Observed {
+        let mut round_trip_times = vec![];
+        let (mut packets, mut packets_lost) = (0, 0);
+        let (mut inbound, mut receiving) = (0, 0);
+        let mut connection_failures = 0;
+
+        for pc in self.peer_connections.iter() {
+            let sample = &pc.sample;
+            round_trip_times.extend(sample.round_trip_time_ms);
+            packets += sample.packets_received + sample.packets_lost + sample.packets_sent;
+            packets_lost += sample.packets_lost + sample.remote_packets_lost;
+
+            if sample.has_inbound {
+                inbound += 1;
+
+                if let VideoReceiveState::Receiving(_) = pc.state.video_receive {
+                    receiving += 1;
+                }
+            }
+
+            if matches!(pc.connection_state(), Some(ConnectionState::Failed)) {
+                connection_failures += 1;
+            }
+        }
+
+        round_trip_times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        Observed {
+            p95_rtt_ms: percentile(&round_trip_times, 95.0),
+            packet_loss_percent: Some(packets)
+                .filter(|packets| *packets > 0)
+                .map(|packets| packets_lost as f64 * 100.0 / packets as f64),
+            receiving_percent: Some(inbound)
+                .filter(|inbound| *inbound > 0)
+                .map(|inbound| receiving as f64 * 100.0 / inbound as f64),
+            connection_failures,
+        }
+    }
+
+    /// Check the thresholds against the latest stats once the warmup is over.
+    pub(crate) fn evaluate_thresholds(&self) {
+        let thresholds = match &self.thresholds {
+            Some(thresholds) => thresholds,
+            None => return,
+        };
+        let elapsed = self
+            .start_time
+            .and_then(|start_time| start_time.elapsed().ok());
+
+        if !matches!(elapsed, Some(elapsed) if elapsed >= thresholds.warmup) {
+            return;
+        }
+
+        let exceeded = thresholds.check(&self.observe());
+        let newly_violated = self.verdict.lock().record(exceeded);
+
+        for threshold in newly_violated {
+            session_log!(
+                self.logs,
+                Level::WARN,
+                None,
+                "Session {} violated its {} threshold",
+                self.id,
+                threshold
+            );
+        }
+    }
+
+    /// Add the latest stats of a peer connection to the record of the current run.
+    pub(crate) fn record_sample(&self, pc: &PeerConnectionManager) {
+        if let Some(mut record) = self.records.get_mut(&pc.id) {
+            let failed = matches!(pc.connection_state(), Some(ConnectionState::Failed));
+            record.add(&pc.sample, failed);
+        }
+    }
 
-    //     let peer_connection = self.get_peer_connection(id)?;
-    //     let video_sender_stats = peer_connection.get_stats().await?;
-    //     let stats = video_sender_stats.into();
+    /// The verdict of the current run, None when the session has no thresholds.
+    pub(crate) fn verdict(&self) -> Option<Verdict> {
+        self.thresholds
+            .as_ref()
+            .map(|_| self.verdict.lock().clone())
+    }
 
-    //     info!(
-    //         "Stats for session {} pc {}: {:?}",
-    //         self.id, id, video_sender_stats
-    //     );
+    /// How long the peer connections of the current run took to reach each milestone
+    pub(crate) fn setup_latencies(&self) -> Vec<SetupLatency> {
+        let lifecycles: Vec<Arc<Lifecycle>> = self
+            .lifecycles
+            .iter()
+            .map(|lifecycle| lifecycle.value().clone())
+            .collect();
 
-    //     Ok(stats)
-    // }
+        setup_latencies(lifecycles.iter().map(|lifecycle| lifecycle.as_ref()))
+    }
 
     pub(crate) fn elapsed_time(&self) -> Option<u64> {
         match self.state {
             SessionState::Created => None,
-            SessionState::Started => elapsed(self.start_time, Some(SystemTime::now())),
+            SessionState::Started | SessionState::Paused => {
+                elapsed(self.start_time, Some(SystemTime::now()))
+            }
             SessionState::Stopped => elapsed(self.start_time, self.stop_time),
         }
     }
@@ -270,7 +673,7 @@ macro_rules! call_session {
             .data
             .sessions
             .get_mut(&$session_id.clone())
-            .ok_or_else(|| crate::error::ServerError::InvalidSessionError($session_id.to_string()))?
+            .ok_or_else(|| $crate::error::ServerError::InvalidSessionError($session_id.to_string()))?
             .$fn($($args),*)
     };
 }
@@ -282,7 +685,9 @@ macro_rules! get_session_attribute {
             .data
             .sessions
             .get(&$session_id)
-            .ok_or_else(|| crate::error::ServerError::InvalidSessionError($session_id.to_string()))?
+            .ok_or_else(|| {
+                $crate::error::ServerError::InvalidSessionError($session_id.to_string())
+            })?
             .$attr
     };
 }
@@ -333,16 +738,113 @@ pub(crate) mod tests {
         assert_eq!(SessionState::Stopped, session.state);
     }
 
-    #[tokio::test]
-    async fn it_exports_peer_connection_stats() {
-        // tracing_subscriber::fmt::init();
+    #[test]
+    fn it_closes_peer_connections_when_stopped() {
         let (session_id, data) = new_session();
         let session = &mut *data.sessions.get_mut(&session_id).unwrap();
         session.start().unwrap();
+        let pc = new_peer_connection().0;
+        let pc_id = pc.id.clone();
+        session.add_peer_connection(pc).unwrap();
+        session.stop().unwrap();
+
+        assert_eq!(0, session.peer_connections.len());
+        assert!(matches!(
+            session.get_peer_connection(&pc_id),
+            Err(ServerError::InvalidStateError(_))
+        ));
+        assert!(matches!(
+            session.add_peer_connection(new_peer_connection().0),
+            Err(ServerError::InvalidStateError(_))
+        ));
+    }
 
+    #[test]
+    fn it_lists_peer_connections() {
+        let (session_id, data) = new_session();
+        let session = &mut *data.sessions.get_mut(&session_id).unwrap();
         let pc = new_peer_connection().0;
+        let pc_id = pc.id.clone();
         session.add_peer_connection(pc).unwrap();
-        session.export_peer_connection_stats(true).await;
+
+        let peer_connections = session.list_peer_connections();
+        assert_eq!(1, peer_connections.len());
+        assert_eq!(pc_id, peer_connections[0].id);
+        assert_eq!("NotSending", peer_connections[0].send_state);
+        assert_eq!("NotReceiving", peer_connections[0].receive_state);
+    }
+
+    #[test]
+    fn it_parses_a_session_state() {
+        assert_eq!(SessionState::Paused, "Paused".parse().unwrap());
+        assert_eq!(SessionState::Stopped, "stopped".parse().unwrap());
+        assert!(matches!(
+            "running".parse::<SessionState>(),
+            Err(ServerError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_a_duplicate_peer_connection() {
+        let (session_id, data) = new_session();
+        let session = &mut *data.sessions.get_mut(&session_id).unwrap();
+        let pc = new_peer_connection().0;
+        let pc_id = pc.id.clone();
+        session.add_peer_connection(pc).unwrap();
+
+        let (mut duplicate, _pool, _source) = new_peer_connection();
+        duplicate.id = pc_id.clone();
+
+        assert!(matches!(
+            session.add_peer_connection(duplicate),
+            Err(ServerError::PeerConnectionAlreadyExists(_))
+        ));
+        assert_eq!(1, session.peer_connections.len());
+    }
+
+    #[test]
+    fn it_pauses_and_resumes_a_session() {
+        let (session_id, data) = new_session();
+        let session = &mut *data.sessions.get_mut(&session_id).unwrap();
+        assert!(session.pause().is_err());
+
+        session.start().unwrap();
+        session.pause().unwrap();
+        assert_eq!(SessionState::Paused, session.state);
+        assert!(session.start().is_err());
+
+        session.resume().unwrap();
+        assert_eq!(SessionState::Started, session.state);
+        assert!(session.resume().is_err());
+    }
+
+    #[test]
+    fn it_restarts_a_stopped_session() {
+        let (session_id, data) = new_session();
+        let session = &mut *data.sessions.get_mut(&session_id).unwrap();
+        session.start().unwrap();
+        session.stop().unwrap();
+        let first_start_time = session.start_time;
+
+        session.start().unwrap();
+        assert_eq!(SessionState::Started, session.state);
+        assert_eq!(2, session.run);
+        assert_eq!(None, session.stop_time);
+        assert_ne!(first_start_time, session.start_time);
+    }
+
+    #[tokio::test]
+    async fn it_exports_peer_connection_stats() {
+        // tracing_subscriber::fmt::init();
+        let (session_id, data) = new_session();
+        {
+            let session = &mut *data.sessions.get_mut(&session_id).unwrap();
+            session.start().unwrap();
+
+            let pc = new_peer_connection().0;
+            session.add_peer_connection(pc).unwrap();
+        }
+        crate::collector::export_session_stats(&data, &session_id, true).await;
 
         // TODO: come up with an assertion, just testing we don't get an err
     }
@@ -358,6 +860,21 @@ pub(crate) mod tests {
         assert!(stats.is_ok());
     }
 
+    #[tokio::test]
+    async fn it_creates_a_loopback_pair() {
+        let (session_id, data) = new_session();
+        let session = data.get_session(&session_id).unwrap();
+        let (sender_id, receiver_id) = (nanoid!(), nanoid!());
+        session
+            .create_loopback_pair(sender_id.clone(), receiver_id.clone(), "loopback".into())
+            .await
+            .unwrap();
+
+        assert_eq!(session.peer_connections.len(), 2);
+        assert!(session.get_peer_connection(&sender_id).is_ok());
+        assert!(session.get_peer_connection(&receiver_id).is_ok());
+    }
+
     #[test]
     fn it_creates_a_peer_connection() {
         tracing_subscriber::fmt::init();
@@ -0,0 +1,64 @@
+use crate::config::CONFIG;
+use crate::data::SharedState;
+use crate::error::{Result, ServerError};
+use futures::Future;
+use std::time::Duration;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, warn};
+
+/// Wait for SIGINT or SIGTERM.
+pub(crate) async fn signal_received() -> Result<()> {
+    let mut terminate =
+        signal(SignalKind::terminate()).map_err(|e| ServerError::InternalError(e.to_string()))?;
+
+    select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate.recv() => info!("Received SIGTERM"),
+    }
+
+    Ok(())
+}
+
+/// Resolves once a shutdown is requested, for the servers to stop accepting connections.
+pub(crate) async fn requested(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        // the sender is only dropped when the server exits
+        if shutdown.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Stop the servers and every running session, giving both the drain
+/// timeout to finish before giving up on them.
+pub(crate) async fn drain(
+    shared_state: &SharedState,
+    shutdown: watch::Sender<bool>,
+    servers: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    let drain_timeout = Duration::from_secs(CONFIG.drain_timeout_s);
+    let deadline = Instant::now() + drain_timeout;
+
+    info!("Attempting to shut down within {:?}", drain_timeout);
+
+    shutdown.send(true).ok();
+
+    if timeout_at(deadline, shared_state.data.stop_sessions())
+        .await
+        .is_err()
+    {
+        warn!("Timed out stopping sessions");
+    }
+
+    // in-flight requests, e.g. observer streams, may not finish on their own
+    match timeout_at(deadline, servers).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("Timed out waiting for in-flight requests");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Service level thresholds of a session, checked against its stats each time
+//! they're collected.  A threshold that was violated at any point of a run
+//! fails the verdict of that run.
+
+use crate::server::webrtc;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+pub(crate) const MAX_P95_RTT_MS: &str = "max_p95_rtt_ms";
+pub(crate) const MAX_PACKET_LOSS_PERCENT: &str = "max_packet_loss_percent";
+pub(crate) const MIN_RECEIVING_PERCENT: &str = "min_receiving_percent";
+pub(crate) const NO_CONNECTION_FAILURES: &str = "no_connection_failures";
+
+/// The thresholds of a session, None when not checked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Thresholds {
+    pub(crate) max_p95_rtt_ms: Option<f64>,
+    pub(crate) max_packet_loss_percent: Option<f64>,
+    pub(crate) min_receiving_percent: Option<f64>,
+    pub(crate) no_connection_failures: bool,
+    /// Time after the start of a run before the thresholds are checked
+    pub(crate) warmup: Duration,
+}
+
+fn positive(value: f64) -> Option<f64> {
+    Some(value).filter(|value| *value > 0.0)
+}
+
+impl From<webrtc::Thresholds> for Thresholds {
+    fn from(thresholds: webrtc::Thresholds) -> Self {
+        Self {
+            max_p95_rtt_ms: positive(thresholds.max_p95_rtt_ms),
+            max_packet_loss_percent: positive(thresholds.max_packet_loss_percent),
+            min_receiving_percent: positive(thresholds.min_receiving_percent),
+            no_connection_failures: thresholds.no_connection_failures,
+            warmup: Duration::from_secs(thresholds.warmup_s.into()),
+        }
+    }
+}
+
+/// What the thresholds are checked against, None when nothing was measured.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Observed {
+    pub(crate) p95_rtt_ms: Option<f64>,
+    pub(crate) packet_loss_percent: Option<f64>,
+    pub(crate) receiving_percent: Option<f64>,
+    pub(crate) connection_failures: u32,
+}
+
+/// A threshold that was exceeded, and the worst value seen.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Violation {
+    pub(crate) limit: f64,
+    pub(crate) value: f64,
+    pub(crate) first_violated: SystemTime,
+}
+
+impl Thresholds {
+    /// The thresholds the observed values exceed, with their limit and value.
+    pub(crate) fn check(&self, observed: &Observed) -> Vec<(&'static str, f64, f64)> {
+        let mut exceeded = vec![];
+
+        if let (Some(limit), Some(value)) = (self.max_p95_rtt_ms, observed.p95_rtt_ms) {
+            if value > limit {
+                exceeded.push((MAX_P95_RTT_MS, limit, value));
+            }
+        }
+
+        if let (Some(limit), Some(value)) =
+            (self.max_packet_loss_percent, observed.packet_loss_percent)
+        {
+            if value > limit {
+                exceeded.push((MAX_PACKET_LOSS_PERCENT, limit, value));
+            }
+        }
+
+        if let (Some(limit), Some(value)) = (self.min_receiving_percent, observed.receiving_percent)
+        {
+            if value < limit {
+                exceeded.push((MIN_RECEIVING_PERCENT, limit, value));
+            }
+        }
+
+        if self.no_connection_failures && observed.connection_failures > 0 {
+            exceeded.push((
+                NO_CONNECTION_FAILURES,
+                0.0,
+                observed.connection_failures as f64,
+            ));
+        }
+
+        exceeded
+    }
+}
+
+/// The outcome of the checks of a run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Verdict {
+    pub(crate) evaluations: u64,
+    pub(crate) violations: BTreeMap<&'static str, Violation>,
+}
+
+impl Verdict {
+    /// Record a check, returning the thresholds that were exceeded for the first time.
+    pub(crate) fn record(&mut self, exceeded: Vec<(&'static str, f64, f64)>) -> Vec<&'static str> {
+        self.evaluations += 1;
+        let mut newly_violated = vec![];
+
+        for (threshold, limit, value) in exceeded {
+            let violation = self.violations.entry(threshold).or_insert_with(|| {
+                newly_violated.push(threshold);
+                Violation {
+                    limit,
+                    value,
+                    first_violated: SystemTime::now(),
+                }
+            });
+
+            // the minimum thresholds are the only ones whose limit is above the value
+            let worse = if value < limit {
+                value < violation.value
+            } else {
+                value > violation.value
+            };
+
+            if worse {
+                violation.value = value;
+            }
+        }
+
+        newly_violated
+    }
+
+    pub(crate) fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl From<&Verdict> for webrtc::Verdict {
+    fn from(verdict: &Verdict) -> Self {
+        Self {
+            passed: verdict.passed(),
+            evaluations: verdict.evaluations,
+            violations: verdict
+                .violations
+                .iter()
+                .map(|(threshold, violation)| webrtc::Violation {
+                    threshold: threshold.to_string(),
+                    limit: violation.limit,
+                    value: violation.value,
+                    first_violated: Some(violation.first_violated.into()),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thresholds() -> Thresholds {
+        webrtc::Thresholds {
+            max_p95_rtt_ms: 200.0,
+            max_packet_loss_percent: 2.0,
+            min_receiving_percent: 95.0,
+            no_connection_failures: true,
+            warmup_s: 0,
+        }
+        .into()
+    }
+
+    #[test]
+    fn it_passes_within_the_thresholds() {
+        let observed = Observed {
+            p95_rtt_ms: Some(120.0),
+            packet_loss_percent: Some(0.5),
+            receiving_percent: Some(100.0),
+            connection_failures: 0,
+        };
+
+        assert!(thresholds().check(&observed).is_empty());
+        assert!(thresholds().check(&Observed::default()).is_empty());
+    }
+
+    #[test]
+    fn it_keeps_the_worst_value_of_a_violation() {
+        let thresholds = thresholds();
+        let mut verdict = Verdict::default();
+        let observed = Observed {
+            p95_rtt_ms: Some(250.0),
+            receiving_percent: Some(90.0),
+            ..Default::default()
+        };
+
+        let newly_violated = verdict.record(thresholds.check(&observed));
+        assert_eq!(vec![MAX_P95_RTT_MS, MIN_RECEIVING_PERCENT], newly_violated);
+
+        let recovered = Observed {
+            p95_rtt_ms: Some(100.0),
+            receiving_percent: Some(80.0),
+            ..Default::default()
+        };
+        assert!(verdict.record(thresholds.check(&recovered)).is_empty());
+
+        assert!(!verdict.passed());
+        assert_eq!(2, verdict.evaluations);
+        assert_eq!(250.0, verdict.violations[MAX_P95_RTT_MS].value);
+        assert_eq!(80.0, verdict.violations[MIN_RECEIVING_PERCENT].value);
+    }
+}
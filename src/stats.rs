@@ -1,12 +1,15 @@
 use crate::error::Result;
 use crate::helpers::systemtime_to_timestamp;
+use crate::lifecycle::SetupLatency;
+use crate::slo::Verdict;
 
 use crate::session::{PeerConnectionState, Session, SessionState};
 use libwebrtc::peer_connection::PeerConnectionStats;
 // use libwebrtc_sys::ffi::ArcasVideoSenderStats;
 
 use libwebrtc::transceiver::VideoTransceiver;
-use std::time::SystemTime;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug)]
 pub(crate) struct SessionStats {
@@ -18,10 +21,20 @@ pub(crate) struct SessionStats {
     pub(crate) start_time: Option<SystemTime>,
     pub(crate) stop_time: Option<SystemTime>,
     pub(crate) elapsed_time: u64,
+    pub(crate) run: u32,
+    pub(crate) stats_interval: Duration,
+    pub(crate) stats_ticks: u64,
+    pub(crate) stats_late_ticks: u64,
+    pub(crate) stats_skipped_ticks: u64,
+    pub(crate) stats_last_lateness_ms: u64,
+    pub(crate) setup_latencies: Vec<SetupLatency>,
+    pub(crate) quality_limited_senders: QualityLimitedSenders,
 }
 
 impl From<&Session> for SessionStats {
     fn from(session: &Session) -> SessionStats {
+        let ticks = &session.stats_ticks;
+
         SessionStats {
             id: session.id.clone(),
             name: session.name.clone(),
@@ -31,6 +44,14 @@ impl From<&Session> for SessionStats {
             start_time: session.start_time,
             stop_time: session.stop_time,
             elapsed_time: session.elapsed_time().unwrap_or(0),
+            run: session.run,
+            stats_interval: session.stats_interval(),
+            stats_ticks: ticks.ticks.load(Ordering::Relaxed),
+            stats_late_ticks: ticks.late.load(Ordering::Relaxed),
+            stats_skipped_ticks: ticks.skipped.load(Ordering::Relaxed),
+            stats_last_lateness_ms: ticks.last_lateness_ms.load(Ordering::Relaxed),
+            setup_latencies: session.setup_latencies(),
+            quality_limited_senders: session.quality_limited_senders(),
         }
     }
 }
@@ -46,63 +67,180 @@ impl From<SessionStats> for crate::server::webrtc::SessionStats {
             start_time: systemtime_to_timestamp(session.start_time),
             stop_time: systemtime_to_timestamp(session.stop_time),
             elapsed_time: session.elapsed_time,
+            run: session.run,
+            stats_collection: Some(crate::server::webrtc::StatsCollection {
+                interval_ms: session.stats_interval.as_millis() as u64,
+                ticks: session.stats_ticks,
+                late_ticks: session.stats_late_ticks,
+                skipped_ticks: session.stats_skipped_ticks,
+                last_lateness_ms: session.stats_last_lateness_ms,
+            }),
+            setup_latencies: session
+                .setup_latencies
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            quality_limited_senders: Some(session.quality_limited_senders.into()),
+        }
+    }
+}
+
+/// The latest stats of a peer connection, aggregated by its session.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct PeerConnectionSample {
+    /// Of the slowest outbound stream, None until a receiver report arrives
+    pub(crate) round_trip_time_ms: Option<f64>,
+    pub(crate) packets_sent: u64,
+    pub(crate) remote_packets_lost: u64,
+    pub(crate) packets_received: u64,
+    pub(crate) packets_lost: u64,
+    /// Whether the peer connection has an inbound video stream
+    pub(crate) has_inbound: bool,
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) frames_encoded: u64,
+    pub(crate) frames_decoded: u64,
+    /// Of the most jittery outbound stream, as seen by the remote side
+    pub(crate) jitter_ms: Option<f64>,
+    /// What limits the first limited outbound stream, None without any
+    pub(crate) quality_limitation: Option<QualityLimitation>,
+    pub(crate) senders: QualityLimitedSenders,
+}
+
+/// Why libwebrtc lowers the resolution or frame rate of an outbound stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, strum::ToString)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum QualityLimitation {
+    None,
+    Cpu,
+    Bandwidth,
+    Other,
+}
+
+impl From<u32> for QualityLimitation {
+    fn from(reason: u32) -> Self {
+        match reason {
+            0 => QualityLimitation::None,
+            1 => QualityLimitation::Cpu,
+            2 => QualityLimitation::Bandwidth,
+            _ => QualityLimitation::Other,
+        }
+    }
+}
+
+/// The outbound streams by what limits their quality.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct QualityLimitedSenders {
+    pub(crate) unlimited: u32,
+    pub(crate) cpu: u32,
+    pub(crate) bandwidth: u32,
+    pub(crate) other: u32,
+    /// Times libwebrtc changed the resolution to cope with a limitation
+    pub(crate) resolution_changes: u64,
+}
+
+impl QualityLimitedSenders {
+    pub(crate) fn add_sender(&mut self, reason: u32, resolution_changes: u32) {
+        match QualityLimitation::from(reason) {
+            QualityLimitation::None => self.unlimited += 1,
+            QualityLimitation::Cpu => self.cpu += 1,
+            QualityLimitation::Bandwidth => self.bandwidth += 1,
+            QualityLimitation::Other => self.other += 1,
+        }
+
+        self.resolution_changes += resolution_changes as u64;
+    }
+
+    pub(crate) fn merge(&mut self, other: &QualityLimitedSenders) {
+        self.unlimited += other.unlimited;
+        self.cpu += other.cpu;
+        self.bandwidth += other.bandwidth;
+        self.other += other.other;
+        self.resolution_changes += other.resolution_changes;
+    }
+}
+
+impl From<QualityLimitedSenders> for crate::server::webrtc::QualityLimitedSenders {
+    fn from(senders: QualityLimitedSenders) -> Self {
+        Self {
+            unlimited: senders.unlimited,
+            cpu: senders.cpu,
+            bandwidth: senders.bandwidth,
+            other: senders.other,
+            resolution_changes: senders.resolution_changes,
         }
     }
 }
 
-// impl From<PeerConnectionStats> for crate::server::webrtc::PeerConnectionStats {
-//     fn from(
-//         peer_connection_stats: PeerConnectionStats,
-//     ) -> crate::server::webrtc::PeerConnectionStats {
-//         crate::server::webrtc::PeerConnectionStats {
-//             id: peer_connection_stats.id.clone(),
-//             name: peer_connection_stats.name.clone(),
-//             video_sender: peer_connection_stats
-//                 .video_sender
-//                 .into_iter()
-//                 .map(|stats| stats.into())
-//                 .collect(),
-//         }
-//     }
-// }
-
-// impl From<ArcasVideoSenderStats> for PeerConnectionStats {
-//     fn from(
-//         video_sender_stats: ArcasVideoSenderStats,
-//     ) -> crate::server::webrtc::PeerConnectionStats {
-//         crate::server::webrtc::PeerConnectionStats {
-//             ssrc: video_sender_stats.ssrc,
-//             packets_sent: video_sender_stats.packets_sent,
-//             bytes_sent: video_sender_stats.bytes_sent,
-//             frames_encoded: video_sender_stats.frames_encoded,
-//             key_frames_encoded: video_sender_stats.key_frames_encoded,
-//             total_encode_time: video_sender_stats.total_encode_time,
-//             frame_width: video_sender_stats.frame_width,
-//             frame_height: video_sender_stats.frame_height,
-//             retransmitted_packets_sent: video_sender_stats.retransmitted_packets_sent,
-//             retransmitted_bytes_sent: video_sender_stats.retransmitted_bytes_sent,
-//             total_packet_send_delay: video_sender_stats.total_packet_send_delay,
-//             nack_count: video_sender_stats.nack_count,
-//             fir_count: video_sender_stats.fir_count,
-//             pli_count: video_sender_stats.pli_count,
-//             quality_limitation_reason: video_sender_stats.quality_limitation_reason,
-//             quality_limitation_resolution_changes: video_sender_stats
-//                 .quality_limitation_resolution_changes,
-//             remote_packets_lost: video_sender_stats.remote_packets_lost,
-//             remote_jitter: video_sender_stats.remote_jitter,
-//             remote_round_trip_time: video_sender_stats.remote_round_trip_time,
-//         }
-//     }
-// }
+fn max_of(values: impl Iterator<Item = f64>) -> Option<f64> {
+    values.fold(None, |max: Option<f64>, value| {
+        Some(max.map_or(value, |max| max.max(value)))
+    })
+}
+
+impl From<&PeerConnectionStats> for PeerConnectionSample {
+    fn from(stats: &PeerConnectionStats) -> Self {
+        let senders = &stats.video_sender_stats;
+        let receivers = &stats.video_receiver_stats;
+
+        Self {
+            round_trip_time_ms: max_of(
+                senders
+                    .iter()
+                    .map(|stat| stat.remote_round_trip_time * 1000.0)
+                    .filter(|round_trip_time| *round_trip_time > 0.0),
+            ),
+            packets_sent: senders.iter().map(|stat| stat.packets_sent as u64).sum(),
+            remote_packets_lost: senders
+                .iter()
+                .map(|stat| stat.remote_packets_lost.max(0) as u64)
+                .sum(),
+            packets_received: receivers
+                .iter()
+                .map(|stat| stat.packets_received as u64)
+                .sum(),
+            packets_lost: receivers
+                .iter()
+                .map(|stat| stat.packets_lost.max(0) as u64)
+                .sum(),
+            has_inbound: !receivers.is_empty(),
+            bytes_sent: senders.iter().map(|stat| stat.bytes_sent).sum(),
+            bytes_received: receivers.iter().map(|stat| stat.bytes_received).sum(),
+            frames_encoded: senders.iter().map(|stat| stat.frames_encoded as u64).sum(),
+            frames_decoded: receivers
+                .iter()
+                .map(|stat| stat.frames_decoded as u64)
+                .sum(),
+            jitter_ms: max_of(senders.iter().map(|stat| stat.remote_jitter * 1000.0)),
+            quality_limitation: senders
+                .iter()
+                .map(|stat| QualityLimitation::from(stat.quality_limitation_reason))
+                .find(|limitation| *limitation != QualityLimitation::None)
+                .or_else(|| senders.first().map(|_| QualityLimitation::None)),
+            senders: senders
+                .iter()
+                .fold(QualityLimitedSenders::default(), |mut limited, stat| {
+                    limited.add_sender(
+                        stat.quality_limitation_reason,
+                        stat.quality_limitation_resolution_changes,
+                    );
+                    limited
+                }),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct Stats {
     pub(crate) session: SessionStats,
+    /// None when the session has no thresholds
+    pub(crate) verdict: Option<Verdict>,
 }
 
 pub(crate) async fn get_stats(session: &Session) -> Result<Stats> {
     let stats = Stats {
         session: session.into(),
+        verdict: session.verdict(),
     };
 
     Ok(stats)
@@ -114,19 +252,6 @@ pub(crate) async fn _get_video_transceiver_stats(
     Ok(tscv.get_stats().await?)
 }
 
-// pub(crate) async fn get_peer_connection_stats(
-//     peer_connection: &PeerConnectionManager,
-// ) -> Result<PeerConnectionStats> {
-//     let video_sender = peer_connection.get_stats().await?;
-//     let peer_connection_stats = PeerConnectionStats {
-//         id: peer_connection.id.clone(),
-//         name: peer_connection.name.clone(),
-//         video_sender,
-//     };
-
-//     Ok(peer_connection_stats)
-// }
-
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
@@ -187,6 +312,36 @@ pub(crate) mod tests {
         assert_eq!(2, stats.session.elapsed_time);
     }
 
+    #[test]
+    fn it_counts_quality_limited_senders() {
+        let sender = |reason, resolution_changes| ArcasVideoSenderStats {
+            quality_limitation_reason: reason,
+            quality_limitation_resolution_changes: resolution_changes,
+            ..video_receiver_stats()
+        };
+        let stats = PeerConnectionStats {
+            video_receiver_stats: vec![],
+            video_sender_stats: vec![sender(0, 0), sender(1, 2), sender(2, 1), sender(2, 0)],
+        };
+        let sample = PeerConnectionSample::from(&stats);
+        assert_eq!(Some(QualityLimitation::Cpu), sample.quality_limitation);
+
+        let mut senders = QualityLimitedSenders::default();
+        senders.merge(&sample.senders);
+        senders.merge(&sample.senders);
+
+        assert_eq!(
+            (2, 2, 4, 0),
+            (
+                senders.unlimited,
+                senders.cpu,
+                senders.bandwidth,
+                senders.other
+            )
+        );
+        assert_eq!(6, senders.resolution_changes);
+    }
+
     // pub(crate) fn new_peer_connection() -> (
     //     PeerConnectionManager,
     //     WebRTCPool,
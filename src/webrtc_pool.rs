@@ -86,4 +86,16 @@ impl WebRTCPool {
         item.value().count.fetch_add(1, Ordering::Relaxed);
         PeerConnectionManager::new(&item.value().peer_connection_factory, *pool_id, id, name)
     }
+
+    /// Release the slot of a closed peer connection on its factory.
+    pub(crate) fn release_peer_connection_manager(&self, pool_id: u32) {
+        if let Some(item) = self.factory_list.get(&pool_id) {
+            item.value()
+                .count
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                    count.checked_sub(1)
+                })
+                .ok();
+        }
+    }
 }